@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::result::Result as StdResult;
+use serde::Deserialize;
+use crate::can::CanAddr;
+use crate::error;
+
+/// Names for addresses on the bus, e.g. `borg-main` instead of `24:23`.
+///
+/// A name may be followed by a port to address another port of the same node, e.g.
+/// `borg-main:02`.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct AddressBook {
+    names: BTreeMap<String, CanAddr>
+}
+
+impl AddressBook {
+    pub fn new() -> AddressBook {
+        AddressBook::default()
+    }
+
+    pub fn insert(&mut self, name: &str, addr: CanAddr) {
+        self.names.insert(name.to_string(), addr);
+    }
+
+    /// Adds all names of `other`, replacing names that exist in both
+    pub fn extend(&mut self, other: AddressBook) {
+        self.names.extend(other.names);
+    }
+
+    pub fn get(&self, name: &str) -> Option<CanAddr> {
+        self.names.get(name).copied()
+    }
+
+    /// Parses a name, a name with a port or an address as accepted by [CanAddr]'s `FromStr`
+    pub fn resolve(&self, s: &str) -> StdResult<CanAddr, error::CanAddrParseError> {
+        if let Some(addr) = self.get(s) {
+            return Ok(addr);
+        }
+        if let Some((name, port)) = s.rsplit_once(':') {
+            if let Some(addr) = self.get(name) {
+                let port = format!("0:{}", port).parse::<CanAddr>()?.port();
+                return CanAddr::new(addr.addr(), port)
+                    .map_err(|_| error::CanAddrParseError);
+            }
+        }
+        s.parse()
+    }
+
+    /// Returns the name of `addr`, or the name of another port of the same node with the port
+    /// appended
+    pub fn name(&self, addr: CanAddr) -> Option<String> {
+        if let Some((name, _)) = self.names.iter().find(|(_, named)| **named == addr) {
+            return Some(name.clone());
+        }
+        self.names.iter()
+            .find(|(_, named)| named.addr() == addr.addr())
+            .map(|(name, _)| format!("{}:{:02x}", name, addr.port()))
+    }
+
+    /// Formats `addr` as hex followed by its name if it has one
+    pub fn display(&self, addr: CanAddr) -> String {
+        match self.name(addr) {
+            Some(name) => format!("{} ({})", addr, name),
+            None => addr.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::address_book::AddressBook;
+    use crate::can::CanAddr;
+
+    #[test]
+    fn test_address_book() {
+        let book: AddressBook = toml::from_str(r#"
+            borg-main = "24:23"
+            lamps-hauptraum = "0t2:0x02"
+        "#).unwrap();
+        let borg = CanAddr::new(0x24, 0x23).unwrap();
+
+        assert_eq!(book.resolve("borg-main").unwrap(), borg);
+        assert_eq!(book.resolve("lamps-hauptraum").unwrap(), CanAddr::new(2, 2).unwrap());
+        assert_eq!(book.resolve("borg-main:0x10").unwrap(), CanAddr::new(0x24, 0x10).unwrap());
+        assert_eq!(book.resolve("30:01").unwrap(), CanAddr::new(0x30, 0x01).unwrap());
+        assert!(book.resolve("borg-side").is_err());
+
+        assert_eq!(book.display(borg), "24:23 (borg-main)");
+        assert_eq!(book.display(CanAddr::new(0x24, 0x10).unwrap()), "24:10 (borg-main:10)");
+        assert_eq!(book.display(CanAddr::new(0x30, 0x01).unwrap()), "30:01");
+
+        assert!(toml::from_str::<AddressBook>("borg-main = \"24:42\"").is_err());
+    }
+}
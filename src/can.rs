@@ -2,6 +2,7 @@ use std::result::Result as StdResult;
 use std::{io, fmt};
 use byteorder::{ReadBytesExt, LittleEndian, WriteBytesExt};
 use std::str::FromStr;
+use serde::{de::Error as _, Deserialize, Deserializer};
 use crate::error::{self, Error, Result};
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -31,23 +32,46 @@ impl fmt::Display for CanAddr {
     }
 }
 
+/// Parses `aa:pp`. Both halves are hex unless prefixed with `0t` for decimal, a `0x` prefix is
+/// accepted as well.
 impl FromStr for CanAddr {
     type Err = error::CanAddrParseError;
 
     fn from_str(s: &str) -> StdResult<Self, Self::Err> {
         let mut split = s.split(':');
         if let (Some(addr), Some(port), None) = (split.next(), split.next(), split.next()) {
-            let num_addr = u8::from_str_radix(addr, 16).
-                map_err(|_| error::CanAddrParseError)?;
-            let num_port = u8::from_str_radix(port, 16).
-                map_err(|_| error::CanAddrParseError)?;
-            Ok(CanAddr(num_addr, num_port))
+            let num_addr = parse_addr_part(addr)?;
+            let num_port = parse_addr_part(port)?;
+            CanAddr::new(num_addr, num_port)
+                .map_err(|_| error::CanAddrParseError)
         } else {
             Err(error::CanAddrParseError)
         }
     }
 }
 
+impl<'de> Deserialize<'de> for CanAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> StdResult<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|_| D::Error::custom(format!("invalid CAN address {:?}", s)))
+    }
+}
+
+pub(crate) fn parse_addr_part(s: &str) -> StdResult<u8, error::CanAddrParseError> {
+    let (digits, radix) = if let Some(digits) = s.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = s.strip_prefix("0t") {
+        (digits, 10)
+    } else {
+        (s, 16)
+    };
+    // from_str_radix would accept a sign
+    if digits.starts_with('+') {
+        return Err(error::CanAddrParseError);
+    }
+    u8::from_str_radix(digits, radix).map_err(|_| error::CanAddrParseError)
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct CanPacket {
     pub src: CanAddr,
@@ -55,17 +79,26 @@ pub struct CanPacket {
     pub payload: Vec<u8>
 }
 
+/// Maximum payload length of a classic CAN frame
+pub const MAX_PAYLOAD: usize = 8;
+
 impl CanPacket {
 
-    pub fn new(src: CanAddr, dest: CanAddr, payload: Vec<u8>) -> CanPacket {
-        CanPacket {
+    /// # Errors
+    /// [Error::PayloadTooLarge] if `payload` does not fit into a classic CAN frame
+    pub fn new(src: CanAddr, dest: CanAddr, payload: Vec<u8>) -> Result<CanPacket> {
+        check_payload_len(payload.len(), MAX_PAYLOAD)?;
+
+        Ok(CanPacket {
             src,
             dest,
             payload
-        }
+        })
     }
 
     pub fn write<W>(&self, write: &mut W) -> Result<()> where W: io::Write {
+        check_payload_len(self.payload.len(), MAX_PAYLOAD)?;
+
         let can_id = can_id_from_tuple(self.src, self.dest);
 
         write.write_u32::<LittleEndian>(can_id)?;
@@ -75,6 +108,8 @@ impl CanPacket {
         Ok(())
     }
 
+    /// # Errors
+    /// [Error::PayloadTooLarge] if the frame claims more payload than a classic CAN frame has
     pub fn read<R>(read: &mut R) -> Result<CanPacket> where R: io::Read {
 
         let can_id = read.read_u32::<LittleEndian>()?;
@@ -84,6 +119,7 @@ impl CanPacket {
         let mut payload = Vec::new();
 
         let dlc = read.read_u8()?;
+        check_payload_len(dlc as usize, MAX_PAYLOAD)?;
         read.read_to_end(&mut payload)?;
 
         if dlc as usize != payload.len() {
@@ -99,6 +135,13 @@ impl CanPacket {
     }
 }
 
+pub(crate) fn check_payload_len(len: usize, max: usize) -> Result<()> {
+    if len > max {
+        return Err(Error::PayloadTooLarge { len, max });
+    }
+    Ok(())
+}
+
 pub fn can_id_from_tuple(src: CanAddr, dest: CanAddr) -> u32 {
     (((src.port() & 0x3f) as u32) << 23) |
         (((dest.port() & 0x30) as u32) << 17) |
@@ -126,11 +169,12 @@ pub fn can_id_to_tuple(id: u32) -> StdResult<(CanAddr, CanAddr), error::InvalidC
 mod test {
     use std::io::{Cursor, Seek, SeekFrom};
     use crate::can::{CanAddr, CanPacket};
+    use crate::error::Error;
 
     #[test]
     fn test_recode() {
         let input = CanPacket {
-            src: CanAddr::new(0x42, 0xaa).unwrap(),
+            src: CanAddr::new(0x42, 0x2a).unwrap(),
             dest: CanAddr::new(0b101010, 0b110011).unwrap(),
             payload: vec![0x13, 0x37]
         };
@@ -145,10 +189,50 @@ mod test {
         assert_eq!(input, output);
     }
 
+    #[test]
+    fn test_payload_limit() {
+        let addr = CanAddr::new(0x42, 0x2a).unwrap();
+        assert!(CanPacket::new(addr, addr, vec![0; 8]).is_ok());
+        match CanPacket::new(addr, addr, vec![0; 12]) {
+            Err(Error::PayloadTooLarge { len: 12, max: 8 }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+
+        let oversize = CanPacket { src: addr, dest: addr, payload: vec![0; 12] };
+        let mut buf = Vec::new();
+        assert!(oversize.write(&mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_read_payload_limit() {
+        let mut input = vec![0x24, 0x00, 0x82, 0x11, 9];
+        input.extend_from_slice(&[0; 9]);
+        match CanPacket::read(&mut Cursor::new(input)) {
+            Err(Error::PayloadTooLarge { len: 9, max: 8 }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
     #[test]
     fn test_can_addr_display() {
         let addr = CanAddr(0x42, 0x3f);
 
         assert_eq!(format!("{}", addr), "42:3f");
     }
+
+    #[test]
+    fn test_can_addr_parse() {
+        let addr = CanAddr(0x24, 0x23);
+        assert_eq!("24:23".parse::<CanAddr>().unwrap(), addr);
+        assert_eq!("0x24:0x23".parse::<CanAddr>().unwrap(), addr);
+        assert_eq!("0t36:0t35".parse::<CanAddr>().unwrap(), addr);
+        assert_eq!("0d:01".parse::<CanAddr>().unwrap(), CanAddr(0x0d, 0x01));
+        assert_eq!("0d1:00".parse::<CanAddr>().unwrap(), CanAddr(0xd1, 0x00));
+        assert!("24:40".parse::<CanAddr>().is_err());
+        assert!("0t256:00".parse::<CanAddr>().is_err());
+        assert!("0t:00".parse::<CanAddr>().is_err());
+        assert!("+1:00".parse::<CanAddr>().is_err());
+        assert!("24".parse::<CanAddr>().is_err());
+    }
 }
\ No newline at end of file
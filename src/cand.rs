@@ -1,34 +1,271 @@
 use std::{fmt, io};
 use std::fmt::Formatter;
 use std::io::{Cursor, Read, Write};
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
-use crate::can::CanPacket;
-use crate::error::Result;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::str::FromStr;
+use crate::can::{check_payload_len, CanPacket, MAX_PAYLOAD};
+use crate::error::{self, Error, Result};
+#[cfg(feature = "async")]
+use bytes::{Buf, BytesMut};
 #[cfg(feature = "async")]
 use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
 
-#[derive(Clone)]
+mod client;
+#[cfg(feature = "async")]
+mod async_client;
+
+pub use self::client::{BusPower, CandClient, DEFAULT_TIMEOUT, DEFAULT_TX_SETTLE};
+#[cfg(feature = "async")]
+pub use self::async_client::{AsyncCandClient, ConnectionEvent, ReconnectPolicy};
+
+/// Maximum payload length of a message in the cand framing
+pub const MAX_MESSAGE_LEN: usize = 0xff;
+
+/// Payload lengths of frame messages by DLC, the CAN ID and DLC take five bytes
+const FRAME_LENGTHS: [usize; MAX_PAYLOAD + 1] = [5, 6, 7, 8, 9, 10, 11, 12, 13];
+
+/// Acceptance filter configuration of the gateway's CAN controller.
+///
+/// A frame with the 29 bit identifier `id` is accepted if `id & mask == filter & mask` holds for
+/// any of the filters. The first mask applies to the first two filters, the second mask to the
+/// remaining four.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct CanFilter {
+    pub masks: [u32; 2],
+    pub filters: [u32; 6]
+}
+
+impl CanFilter {
+    /// A filter configuration that lets every frame pass
+    pub fn accept_all() -> CanFilter {
+        CanFilter {
+            masks: [0; 2],
+            filters: [0; 6]
+        }
+    }
+
+    pub fn matches(&self, id: u32) -> bool {
+        self.filters.iter()
+            .enumerate()
+            .any(|(idx, filter)| {
+                let mask = self.masks[if idx < 2 { 0 } else { 1 }];
+                id & mask == filter & mask
+            })
+    }
+
+    fn read<R: Read>(read: &mut R) -> Result<CanFilter> {
+        let mut filter = CanFilter::accept_all();
+        for mask in filter.masks.iter_mut() {
+            *mask = read.read_u32::<LittleEndian>()?;
+        }
+        for f in filter.filters.iter_mut() {
+            *f = read.read_u32::<LittleEndian>()?;
+        }
+        Ok(filter)
+    }
+
+    fn write<W: Write>(&self, write: &mut W) -> Result<()> {
+        for mask in self.masks.iter().chain(self.filters.iter()) {
+            write.write_u32::<LittleEndian>(*mask)?;
+        }
+        Ok(())
+    }
+}
+
+/// Operating mode of the gateway's CAN controller
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum GatewayMode {
+    Normal = 0,
+    Sleep = 1,
+    /// Transmitted frames are received back without touching the bus
+    Loopback = 2,
+    /// Frames are received but never acknowledged, nothing is transmitted
+    ListenOnly = 3,
+    Config = 4
+}
+
+impl GatewayMode {
+    pub fn from_u8(mode: u8) -> Option<GatewayMode> {
+        match mode {
+            0 => Some(GatewayMode::Normal),
+            1 => Some(GatewayMode::Sleep),
+            2 => Some(GatewayMode::Loopback),
+            3 => Some(GatewayMode::ListenOnly),
+            4 => Some(GatewayMode::Config),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for GatewayMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GatewayMode::Normal => "normal",
+            GatewayMode::Sleep => "sleep",
+            GatewayMode::Loopback => "loopback",
+            GatewayMode::ListenOnly => "listen-only",
+            GatewayMode::Config => "config"
+        })
+    }
+}
+
+impl FromStr for GatewayMode {
+    type Err = error::InvalidGatewayMode;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(GatewayMode::Normal),
+            "sleep" => Ok(GatewayMode::Sleep),
+            "loopback" => Ok(GatewayMode::Loopback),
+            "listen-only" => Ok(GatewayMode::ListenOnly),
+            "config" => Ok(GatewayMode::Config),
+            _ => Err(error::InvalidGatewayMode)
+        }
+    }
+}
+
+/// Reason for the last reset of the gateway, as reported by the AVR's MCUSR register
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ResetCause {
+    PowerOn,
+    External,
+    BrownOut,
+    Watchdog,
+    Jtag,
+    Unknown(u8)
+}
+
+impl ResetCause {
+    /// Decodes the most significant cause if the firmware left several flags set, e.g. after a
+    /// power-on followed by a brown-out
+    pub fn from_u8(cause: u8) -> ResetCause {
+        if cause & 0x08 != 0 {
+            ResetCause::Watchdog
+        } else if cause & 0x04 != 0 {
+            ResetCause::BrownOut
+        } else if cause & 0x02 != 0 {
+            ResetCause::External
+        } else if cause & 0x01 != 0 {
+            ResetCause::PowerOn
+        } else if cause & 0x10 != 0 {
+            ResetCause::Jtag
+        } else {
+            ResetCause::Unknown(cause)
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ResetCause::PowerOn => 0x01,
+            ResetCause::External => 0x02,
+            ResetCause::BrownOut => 0x04,
+            ResetCause::Watchdog => 0x08,
+            ResetCause::Jtag => 0x10,
+            ResetCause::Unknown(cause) => cause
+        }
+    }
+}
+
+impl fmt::Display for ResetCause {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ResetCause::PowerOn => f.write_str("power-on"),
+            ResetCause::External => f.write_str("external"),
+            ResetCause::BrownOut => f.write_str("brown-out"),
+            ResetCause::Watchdog => f.write_str("watchdog"),
+            ResetCause::Jtag => f.write_str("JTAG"),
+            ResetCause::Unknown(cause) => write!(f, "unknown (0x{:02x})", cause)
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Clone)]
 pub enum Message {
+    SetFilter(CanFilter),
     Frame(CanPacket),
-    Reset { cause: u8 },
+    SetMode(GatewayMode),
+    Reset { cause: ResetCause },
     Ping,
     Resync,
     VersionRequest,
     VersionReply { major: u8, minor: u8 },
     FirmwareIdRequest,
     FirmwareIdResponse(String),
+    PacketCountersRequest,
+    PacketCounters { rx_count: u32, tx_count: u32 },
+    ErrorCountersRequest,
+    ErrorCounters { rx_errors: u32, tx_errors: u32 },
     BusPowerRequest,
     BusPowerResponse { v: u16, i: u16, reference: u16, gnd: u16 },
+    ReadCtlReg { reg: u8 },
+    ReadCtlRegReply { reg: u8, value: u8 },
+    WriteCtlReg { reg: u8, value: u8 },
+    ResetCauseRequest,
+    ResetCauseReply(ResetCause),
+    /// The gateway could not put the contained frame on the bus
+    TxFailed(CanPacket),
     Unknown { kind: u8, payload:  Vec<u8> }
 }
 
 impl Message {
+    /// Like [Message::read], but a known message type with an unexpected payload yields
+    /// [Error::MalformedMessage] or [Error::InvalidGatewayMode] instead of [Message::Unknown]
+    pub fn read_strict(kind: u8, buf: &[u8]) -> Result<Message> {
+        let expected = match Message::expected_lengths(kind) {
+            Some(expected) => expected,
+            None => return Message::decode(kind, buf)
+        };
+        let malformed = |expected| Error::MalformedMessage { kind, len: buf.len(), expected };
+        if !expected.contains(&buf.len()) {
+            return Err(malformed(expected));
+        }
+
+        match Message::decode(kind, buf) {
+            Ok(Message::Unknown { kind: 0x12, payload }) => Err(Error::InvalidGatewayMode(payload[0])),
+            Ok(Message::Unknown { .. }) => Err(malformed(expected)),
+            Ok(msg) => Ok(msg),
+            // a frame whose length does not match its DLC, or a DLC beyond a classic CAN frame
+            Err(_) => Err(malformed(match buf[4] as usize {
+                dlc if dlc <= MAX_PAYLOAD => &FRAME_LENGTHS[dlc..=dlc],
+                _ => &[]
+            }))
+        }
+    }
+
+    /// Payload lengths that are valid for the message type `kind`, `None` if the type is not
+    /// known or not restricted
+    fn expected_lengths(kind: u8) -> Option<&'static [usize]> {
+        match kind {
+            0x10 => Some(&[32]),
+            0x11 | 0x1f => Some(&FRAME_LENGTHS),
+            0x12 | 0x14 => Some(&[1]),
+            0x15 | 0x16 => Some(&[0]),
+            0x17 => Some(&[0, 2]),
+            0x19..=0x1b => Some(&[0, 8]),
+            0x1c => Some(&[1, 2]),
+            0x1d => Some(&[2]),
+            0x1e => Some(&[0, 1]),
+            _ => None
+        }
+    }
+
+    /// Decodes a message, a known message type with an unexpected payload yields
+    /// [Message::Unknown]
     pub fn read(kind: u8, buf: &[u8]) -> Result<Message> {
+        Ok(Message::decode(kind, buf).unwrap_or_else(|_| Message::Unknown { kind, payload: Vec::from(buf) }))
+    }
+
+    fn decode(kind: u8, buf: &[u8]) -> Result<Message> {
         let len = buf.len();
         let mut cur = Cursor::new(buf);
         match (kind, len) {
+            (0x10, 32) => Ok(Message::SetFilter(CanFilter::read(&mut cur)?)),
             (0x11, _) => Ok(Message::Frame(CanPacket::read(&mut cur)?)),
-            (0x14, 1) => Ok(Message::Reset { cause: ReadBytesExt::read_u8(&mut cur)? }),
+            (0x12, 1) => match GatewayMode::from_u8(buf[0]) {
+                Some(mode) => Ok(Message::SetMode(mode)),
+                None => Ok(Message::Unknown { kind, payload: Vec::from(buf) })
+            },
+            (0x14, 1) => Ok(Message::Reset { cause: ResetCause::from_u8(ReadBytesExt::read_u8(&mut cur)?) }),
             (0x15, 0) => Ok(Message::Ping),
             (0x16, 0) => Ok(Message::Resync),
             (0x17, 0) => Ok(Message::VersionRequest),
@@ -38,6 +275,16 @@ impl Message {
             }),
             (0x18, 0) => Ok(Message::FirmwareIdRequest),
             (0x18, _) => Ok(Message::FirmwareIdResponse(String::from_utf8_lossy(buf).into_owned())),
+            (0x19, 0) => Ok(Message::PacketCountersRequest),
+            (0x19, 8) => Ok(Message::PacketCounters {
+                rx_count: ReadBytesExt::read_u32::<LittleEndian>(&mut cur)?,
+                tx_count: ReadBytesExt::read_u32::<LittleEndian>(&mut cur)?
+            }),
+            (0x1a, 0) => Ok(Message::ErrorCountersRequest),
+            (0x1a, 8) => Ok(Message::ErrorCounters {
+                rx_errors: ReadBytesExt::read_u32::<LittleEndian>(&mut cur)?,
+                tx_errors: ReadBytesExt::read_u32::<LittleEndian>(&mut cur)?
+            }),
             (0x1b, 0) => Ok(Message::BusPowerRequest),
             (0x1b, 8) => Ok(Message::BusPowerResponse {
                 v: ReadBytesExt::read_u16::<LittleEndian>(&mut cur)?,
@@ -45,13 +292,21 @@ impl Message {
                 reference: ReadBytesExt::read_u16::<LittleEndian>(&mut cur)?,
                 gnd: ReadBytesExt::read_u16::<LittleEndian>(&mut cur)?
             }),
+            (0x1c, 1) => Ok(Message::ReadCtlReg { reg: buf[0] }),
+            (0x1c, 2) => Ok(Message::ReadCtlRegReply { reg: buf[0], value: buf[1] }),
+            (0x1d, 2) => Ok(Message::WriteCtlReg { reg: buf[0], value: buf[1] }),
+            (0x1e, 0) => Ok(Message::ResetCauseRequest),
+            (0x1e, 1) => Ok(Message::ResetCauseReply(ResetCause::from_u8(buf[0]))),
+            (0x1f, _) => Ok(Message::TxFailed(CanPacket::read(&mut cur)?)),
             (_, _) => Ok(Message::Unknown {kind, payload: Vec::from(buf)})
         }
     }
 
     pub fn kind(&self) -> u8 {
         match self {
+            Message::SetFilter(_) => 0x10,
             Message::Frame(_) => 0x11,
+            Message::SetMode(_) => 0x12,
             Message::Reset { .. } => 0x14,
             Message::Ping => 0x15,
             Message::Resync => 0x16,
@@ -60,18 +315,43 @@ impl Message {
             Message::FirmwareIdRequest => 0x18,
             Message::FirmwareIdResponse(_) => 0x18,
             Message::Unknown { kind, .. } => *kind,
+            Message::PacketCountersRequest => 0x19,
+            Message::PacketCounters { .. } => 0x19,
+            Message::ErrorCountersRequest => 0x1a,
+            Message::ErrorCounters { .. } => 0x1a,
             Message::BusPowerRequest => 0x1b,
-            Message::BusPowerResponse { .. } => 0x1b
+            Message::BusPowerResponse { .. } => 0x1b,
+            Message::ReadCtlReg { .. } => 0x1c,
+            Message::ReadCtlRegReply { .. } => 0x1c,
+            Message::WriteCtlReg { .. } => 0x1d,
+            Message::ResetCauseRequest => 0x1e,
+            Message::ResetCauseReply(_) => 0x1e,
+            Message::TxFailed(_) => 0x1f
         }
     }
 
+    /// # Errors
+    /// [Error::PayloadTooLarge] if the encoded message would not fit into the single length byte
+    /// of the cand framing, or if a contained CAN frame exceeds [crate::can::MAX_PAYLOAD]
     pub fn write<W: Write>(&self, write: &mut W) -> Result<()> {
         match self {
-            Message::Frame(frame) => {
+            Message::FirmwareIdResponse(id) => check_payload_len(id.len(), MAX_MESSAGE_LEN)?,
+            Message::Unknown { payload, .. } => check_payload_len(payload.len(), MAX_MESSAGE_LEN)?,
+            _ => {}
+        }
+
+        match self {
+            Message::SetFilter(filter) => {
+                filter.write(write)?;
+            }
+            Message::Frame(frame) | Message::TxFailed(frame) => {
                 frame.write(write)?;
             }
-            Message::Reset { cause } => {
-                write.write_u8(*cause)?;
+            Message::SetMode(mode) => {
+                write.write_u8(*mode as u8)?;
+            }
+            Message::Reset { cause } | Message::ResetCauseReply(cause) => {
+                write.write_u8(cause.to_u8())?;
             }
             Message::Ping => {} // Nothing to do
             Message::Resync => {} // Nothing to do
@@ -85,7 +365,17 @@ impl Message {
                 write.write_all(id.as_bytes())?;
             }
             Message::Unknown { payload, .. } => {
-                write.write_all(&payload)?;
+                write.write_all(payload)?;
+            }
+            Message::PacketCountersRequest => {}
+            Message::PacketCounters { rx_count, tx_count } => {
+                write.write_u32::<LittleEndian>(*rx_count)?;
+                write.write_u32::<LittleEndian>(*tx_count)?;
+            }
+            Message::ErrorCountersRequest => {}
+            Message::ErrorCounters { rx_errors, tx_errors } => {
+                write.write_u32::<LittleEndian>(*rx_errors)?;
+                write.write_u32::<LittleEndian>(*tx_errors)?;
             }
             Message::BusPowerRequest => {}
             Message::BusPowerResponse { v, i, reference, gnd } => {
@@ -94,19 +384,65 @@ impl Message {
                 write.write_u16::<LittleEndian>(*reference)?;
                 write.write_u16::<LittleEndian>(*gnd)?;
             }
+            Message::ReadCtlReg { reg } => {
+                write.write_u8(*reg)?;
+            }
+            Message::ReadCtlRegReply { reg, value } | Message::WriteCtlReg { reg, value } => {
+                write.write_u8(*reg)?;
+                write.write_u8(*value)?;
+            }
+            Message::ResetCauseRequest => {}
         }
         Ok(())
     }
+
+    /// Length of the payload [Message::write] produces, excluding the cand framing
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Message::SetFilter(_) => 32,
+            Message::Frame(frame) | Message::TxFailed(frame) => 5 + frame.payload.len(),
+            Message::SetMode(_) => 1,
+            Message::Reset { .. } => 1,
+            Message::Ping => 0,
+            Message::Resync => 0,
+            Message::VersionRequest => 0,
+            Message::VersionReply { .. } => 2,
+            Message::FirmwareIdRequest => 0,
+            Message::FirmwareIdResponse(id) => id.len(),
+            Message::PacketCountersRequest => 0,
+            Message::PacketCounters { .. } => 8,
+            Message::ErrorCountersRequest => 0,
+            Message::ErrorCounters { .. } => 8,
+            Message::BusPowerRequest => 0,
+            Message::BusPowerResponse { .. } => 8,
+            Message::ReadCtlReg { .. } => 1,
+            Message::ReadCtlRegReply { .. } => 2,
+            Message::WriteCtlReg { .. } => 2,
+            Message::ResetCauseRequest => 0,
+            Message::ResetCauseReply(_) => 1,
+            Message::Unknown { payload, .. } => payload.len()
+        }
+    }
 }
 
 impl fmt::Debug for Message {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
+            Message::SetFilter(filter) => {
+                let mut tuple = f.debug_tuple("Message::SetFilter");
+                tuple.field(filter);
+                tuple.finish()
+            }
             Message::Frame(frame) => {
                 let mut tuple = f.debug_tuple("Message::Frame");
                 tuple.field(frame);
                 tuple.finish()
             }
+            Message::SetMode(mode) => {
+                let mut tuple = f.debug_tuple("Message::SetMode");
+                tuple.field(mode);
+                tuple.finish()
+            }
             Message::Ping => {
                 f.write_str("Message::Ping")
             }
@@ -141,6 +477,24 @@ impl fmt::Debug for Message {
                 s.field("payload", payload);
                 s.finish()
             }
+            Message::PacketCountersRequest => {
+                f.write_str("Message::PacketCountersRequest")
+            }
+            Message::PacketCounters { rx_count, tx_count } => {
+                let mut tuple = f.debug_struct("Message::PacketCounters");
+                tuple.field("rx_count", rx_count);
+                tuple.field("tx_count", tx_count);
+                tuple.finish()
+            }
+            Message::ErrorCountersRequest => {
+                f.write_str("Message::ErrorCountersRequest")
+            }
+            Message::ErrorCounters { rx_errors, tx_errors } => {
+                let mut tuple = f.debug_struct("Message::ErrorCounters");
+                tuple.field("rx_errors", rx_errors);
+                tuple.field("tx_errors", tx_errors);
+                tuple.finish()
+            }
             Message::BusPowerRequest => {
                 f.write_str("Message::BusPowerRequest")
             }
@@ -152,12 +506,237 @@ impl fmt::Debug for Message {
                 tuple.field("gnd", gnd);
                 tuple.finish()
             }
+            Message::ReadCtlReg { reg } => {
+                let mut tuple = f.debug_struct("Message::ReadCtlReg");
+                tuple.field("reg", reg);
+                tuple.finish()
+            }
+            Message::ReadCtlRegReply { reg, value } => {
+                let mut tuple = f.debug_struct("Message::ReadCtlRegReply");
+                tuple.field("reg", reg);
+                tuple.field("value", value);
+                tuple.finish()
+            }
+            Message::WriteCtlReg { reg, value } => {
+                let mut tuple = f.debug_struct("Message::WriteCtlReg");
+                tuple.field("reg", reg);
+                tuple.field("value", value);
+                tuple.finish()
+            }
+            Message::ResetCauseRequest => {
+                f.write_str("Message::ResetCauseRequest")
+            }
+            Message::ResetCauseReply(cause) => {
+                let mut tuple = f.debug_tuple("Message::ResetCauseReply");
+                tuple.field(cause);
+                tuple.finish()
+            }
+            Message::TxFailed(frame) => {
+                let mut tuple = f.debug_tuple("Message::TxFailed");
+                tuple.field(frame);
+                tuple.finish()
+            }
+        }
+    }
+}
+
+/// Incremental decoder for the cand stream that does not perform any IO itself.
+///
+/// Bytes are passed in using [Decoder::feed] in chunks of arbitrary size, incomplete messages
+/// stay buffered until the rest arrives. Complete messages are yielded by iterating the decoder.
+/// The iterator ends as soon as no complete message is left, but continues after more bytes have
+/// been fed.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    pos: usize,
+    strict: bool
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// A decoder that decodes using [Message::read_strict]
+    pub fn new_strict() -> Decoder {
+        Decoder {
+            strict: true,
+            ..Decoder::default()
+        }
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> &mut Decoder {
+        if self.pos > 0 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    /// Number of bytes buffered that do not form a complete message yet
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// Decodes the next complete message, if any.
+    ///
+    /// A message that fails to decode is consumed, so decoding may continue afterwards.
+    pub fn decode(&mut self) -> Option<Result<Message>> {
+        let (len, msg) = decode_framed(&self.buf[self.pos..], self.strict)?;
+        self.pos += len;
+        Some(msg)
+    }
+}
+
+/// Decodes the message at the start of `buf` and returns it together with its framed length, or
+/// `None` if `buf` does not contain a complete message yet
+fn decode_framed(buf: &[u8], strict: bool) -> Option<(usize, Result<Message>)> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let size = buf[0] as usize;
+    let kind = buf[1];
+    if buf.len() < size + 2 {
+        return None;
+    }
+
+    let payload = &buf[2..size + 2];
+    let msg = if strict {
+        Message::read_strict(kind, payload)
+    } else {
+        Message::read(kind, payload)
+    };
+    Some((size + 2, msg))
+}
+
+impl Iterator for Decoder {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decode()
+    }
+}
+
+/// Encoder for the cand stream writing into caller supplied buffers
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Encoder;
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder
+    }
+
+    /// Encodes `msg` including the framing to the start of `buf`.
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// # Errors
+    /// [Error::BufferTooSmall] if `buf` can not hold the encoded message, in addition to the
+    /// errors of [Message::write]
+    pub fn encode(&mut self, msg: &Message, buf: &mut [u8]) -> Result<usize> {
+        let len = msg.encoded_len();
+        check_payload_len(len, MAX_MESSAGE_LEN)?;
+        if buf.len() < len + 2 {
+            return Err(Error::BufferTooSmall { len: buf.len(), needed: len + 2 });
+        }
+
+        buf[0] = len as u8;
+        buf[1] = msg.kind();
+        msg.write(&mut &mut buf[2..len + 2])?;
+        Ok(len + 2)
+    }
+}
+
+/// Codec for using the cand stream with [tokio_util::codec::Framed].
+///
+/// Errors of the stream are transport failures only. Messages that cannot be decoded are yielded
+/// as [Message::Unknown], a strict codec logs and skips them instead, so the stream continues
+/// with the next message either way.
+#[cfg(feature = "async")]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CandCodec {
+    strict: bool
+}
+
+#[cfg(feature = "async")]
+impl CandCodec {
+    pub fn new() -> CandCodec {
+        CandCodec::default()
+    }
+
+    /// A codec that decodes using [Message::read_strict]
+    pub fn new_strict() -> CandCodec {
+        CandCodec {
+            strict: true
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio_util::codec::Decoder for CandCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        while let Some((len, msg)) = decode_framed(src, self.strict) {
+            src.advance(len);
+            match msg {
+                Ok(msg) => return Ok(Some(msg)),
+                Err(e) => log::warn!("Skipping undecodable message: {}", e)
+            }
+        }
+        if let Some(size) = src.first() {
+            src.reserve(*size as usize + 2 - src.len());
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio_util::codec::Encoder<Message> for CandCodec {
+    type Error = Error;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<()> {
+        tokio_util::codec::Encoder::encode(self, &msg, dst)
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio_util::codec::Encoder<&Message> for CandCodec {
+    type Error = Error;
+
+    fn encode(&mut self, msg: &Message, dst: &mut BytesMut) -> Result<()> {
+        let start = dst.len();
+        dst.resize(start + msg.encoded_len() + 2, 0);
+        match Encoder::new().encode(msg, &mut dst[start..]) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                dst.truncate(start);
+                Err(e)
+            }
         }
     }
 }
 
 pub fn read_packet<R: Read>(read: &mut R) -> Result<Option<Message>> {
-    let mut buf = Vec::new();
+    read_packet_with(read, Message::read)
+}
+
+/// Like [read_packet], but decodes using [Message::read_strict].
+///
+/// A malformed message is consumed completely, so reading may continue after an
+/// [Error::MalformedMessage].
+pub fn read_packet_strict<R: Read>(read: &mut R) -> Result<Option<Message>> {
+    read_packet_with(read, Message::read_strict)
+}
+
+fn read_packet_with<R: Read>(read: &mut R, decode: fn(u8, &[u8]) -> Result<Message>) -> Result<Option<Message>> {
     let size = match read.read_u8() {
         Ok(size) => size,
         Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
@@ -167,12 +746,18 @@ pub fn read_packet<R: Read>(read: &mut R) -> Result<Option<Message>> {
             return Err(e.into())
         }
     };
+
+    Ok(Some(read_packet_body(read, size, decode)?))
+}
+
+fn read_packet_body<R: Read>(read: &mut R, size: u8, decode: fn(u8, &[u8]) -> Result<Message>) -> Result<Message> {
+    let mut buf = Vec::new();
     let kind = read.read_u8()?;
 
     buf.resize(size as usize, 0);
     read.read_exact(&mut buf)?;
 
-    Ok(Some(Message::read(kind, &buf)?))
+    decode(kind, &buf)
 }
 
 #[cfg(feature = "async")]
@@ -195,10 +780,16 @@ pub async fn read_packet_async<R: AsyncRead + Unpin>(read: &mut R) -> Result<Opt
     Ok(Some(Message::read(kind, &buf)?))
 }
 
-pub fn write_packet_to_cand<W: io::Write>(w: &mut W, msg: &Message) -> Result<()> {
+fn encode_payload(msg: &Message) -> Result<Vec<u8>> {
     let mut cur = Cursor::new(Vec::new());
     msg.write(&mut cur)?;
     let buf = cur.into_inner();
+    check_payload_len(buf.len(), MAX_MESSAGE_LEN)?;
+    Ok(buf)
+}
+
+pub fn write_packet_to_cand<W: io::Write>(w: &mut W, msg: &Message) -> Result<()> {
+    let buf = encode_payload(msg)?;
 
     w.write_u8(buf.len() as u8)?;
     w.write_u8(msg.kind())?;
@@ -208,12 +799,279 @@ pub fn write_packet_to_cand<W: io::Write>(w: &mut W, msg: &Message) -> Result<()
 
 #[cfg(feature = "async")]
 pub async fn write_packet_to_cand_async<W: AsyncWrite + Unpin>(w: &mut W, msg: &Message) -> Result<()> {
-    let mut cur = Cursor::new(Vec::new());
-    msg.write(&mut cur)?;
-    let buf = cur.into_inner();
+    let buf = encode_payload(msg)?;
 
     w.write_u8(buf.len() as u8).await?;
     w.write_u8(msg.kind()).await?;
     w.write_all(&buf).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use crate::can::{CanAddr, CanPacket};
+    use crate::cand::{CanFilter, Decoder, Encoder, GatewayMode, Message, ResetCause, read_packet, read_packet_strict, write_packet_to_cand};
+    use crate::error::Error;
+
+    fn recode(input: &Message) -> Message {
+        let mut cursor = Cursor::new(Vec::new());
+        write_packet_to_cand(&mut cursor, input).unwrap();
+
+        cursor.set_position(0);
+
+        read_packet(&mut cursor).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_recode_set_filter() {
+        let filter = CanFilter {
+            masks: [0x1fffffff, 0x0000ff00],
+            filters: [1, 2, 3, 4, 5, 0x1fffffff]
+        };
+
+        match recode(&Message::SetFilter(filter)) {
+            Message::SetFilter(output) => assert_eq!(filter, output),
+            other => panic!("unexpected message {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_recode_set_mode() {
+        for mode in 0..5 {
+            let mode = GatewayMode::from_u8(mode).unwrap();
+            match recode(&Message::SetMode(mode)) {
+                Message::SetMode(output) => assert_eq!(mode, output),
+                other => panic!("unexpected message {:?}", other)
+            }
+            assert_eq!(mode, mode.to_string().parse().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_recode_counters() {
+        match recode(&Message::PacketCounters { rx_count: 0xdeadbeef, tx_count: 42 }) {
+            Message::PacketCounters { rx_count: 0xdeadbeef, tx_count: 42 } => {}
+            other => panic!("unexpected message {:?}", other)
+        }
+        match recode(&Message::ErrorCounters { rx_errors: 1, tx_errors: 0x10000 }) {
+            Message::ErrorCounters { rx_errors: 1, tx_errors: 0x10000 } => {}
+            other => panic!("unexpected message {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_recode_reset_cause() {
+        for cause in 0..=0xff {
+            let cause = ResetCause::from_u8(cause);
+            match recode(&Message::Reset { cause }) {
+                Message::Reset { cause: output } => assert_eq!(cause, output),
+                other => panic!("unexpected message {:?}", other)
+            }
+        }
+        match recode(&Message::ResetCauseReply(ResetCause::Watchdog)) {
+            Message::ResetCauseReply(ResetCause::Watchdog) => {}
+            other => panic!("unexpected message {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_combined_reset_cause() {
+        assert_eq!(ResetCause::from_u8(0x05), ResetCause::BrownOut);
+        assert_eq!(ResetCause::from_u8(0x1f), ResetCause::Watchdog);
+        assert_eq!(ResetCause::from_u8(0x13), ResetCause::External);
+        assert_eq!(ResetCause::from_u8(0x10), ResetCause::Jtag);
+        assert_eq!(ResetCause::from_u8(0x20), ResetCause::Unknown(0x20));
+    }
+
+    #[test]
+    fn test_strict_gateway_mode() {
+        match Message::read_strict(0x12, &[0x42]) {
+            Err(Error::InvalidGatewayMode(0x42)) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+        match Message::read_strict(0x12, &[0x42, 0x00]) {
+            Err(Error::MalformedMessage { kind: 0x12, len: 2, .. }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_strict() {
+        let mut cursor = Cursor::new(vec![5, 0x17, 1, 2, 3, 4, 5, 3, 0x1b, 1, 2, 3, 0, 0x15]);
+
+        match read_packet_strict(&mut cursor) {
+            Err(Error::MalformedMessage { kind: 0x17, len: 5, .. }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+        match read_packet_strict(&mut cursor) {
+            Err(Error::MalformedMessage { kind: 0x1b, len: 3, expected }) => assert_eq!(expected, &[0, 8]),
+            other => panic!("unexpected result {:?}", other)
+        }
+        match read_packet_strict(&mut cursor) {
+            Ok(Some(Message::Ping)) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+
+        match Message::read(0x17, &[1, 2, 3, 4, 5]) {
+            Ok(Message::Unknown { kind: 0x17, .. }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+        match Message::read_strict(0x42, &[1, 2, 3]) {
+            Ok(Message::Unknown { kind: 0x42, .. }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_strict_frame() {
+        // DLC 2 with a single payload byte
+        match Message::read_strict(0x11, &[0x24, 0x00, 0x82, 0x11, 2, 0x01]) {
+            Err(Error::MalformedMessage { kind: 0x11, len: 6, expected }) => assert_eq!(expected, &[7]),
+            other => panic!("unexpected result {:?}", other)
+        }
+        match Message::read_strict(0x1f, &[0x24, 0x00, 0x82]) {
+            Err(Error::MalformedMessage { kind: 0x1f, len: 3, .. }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+
+        let mut oversize = vec![0x24, 0x00, 0x82, 0x11, 9];
+        oversize.extend_from_slice(&[0; 9]);
+        match Message::read_strict(0x11, &oversize) {
+            Err(Error::MalformedMessage { kind: 0x11, len: 14, .. }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+        // a DLC beyond a classic CAN frame within the length limit
+        match Message::read_strict(0x11, &[0x24, 0x00, 0x82, 0x11, 9, 0, 0, 0, 0, 0, 0, 0, 0]) {
+            Err(Error::MalformedMessage { kind: 0x11, len: 13, expected }) => assert!(expected.is_empty()),
+            other => panic!("unexpected result {:?}", other)
+        }
+        match Message::read(0x11, &oversize) {
+            Ok(Message::Unknown { kind: 0x11, payload }) => assert_eq!(payload, oversize),
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_oversize_message() {
+        let mut buf = Vec::new();
+        match write_packet_to_cand(&mut buf, &Message::FirmwareIdResponse("x".repeat(300))) {
+            Err(Error::PayloadTooLarge { len: 300, max: 255 }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+        assert!(buf.is_empty());
+
+        let addr = CanAddr::new(0x42, 1).unwrap();
+        let frame = CanPacket { src: addr, dest: addr, payload: vec![0; 12] };
+        assert!(write_packet_to_cand(&mut buf, &Message::Frame(frame)).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decoder() {
+        let addr = CanAddr::new(0x42, 1).unwrap();
+        let input = vec![
+            Message::Ping,
+            Message::Frame(CanPacket::new(addr, addr, vec![1, 2, 3]).unwrap()),
+            Message::FirmwareIdResponse("can-gateway".to_string()),
+            Message::BusPowerRequest
+        ];
+
+        let mut stream = Vec::new();
+        let mut encoder = Encoder::new();
+        for msg in input.iter() {
+            let mut buf = [0; 258];
+            let len = encoder.encode(msg, &mut buf).unwrap();
+            assert_eq!(len, msg.encoded_len() + 2);
+            stream.extend_from_slice(&buf[..len]);
+        }
+
+        let mut decoder = Decoder::new();
+        let mut output = Vec::new();
+        for byte in stream.iter() {
+            output.extend(decoder.feed(&[*byte]).map(|msg| msg.unwrap()));
+        }
+        assert_eq!(decoder.buffered(), 0);
+        assert_eq!(format!("{:?}", input), format!("{:?}", output));
+
+        decoder.feed(&stream[..5]);
+        assert!(decoder.decode().is_some());
+        assert!(decoder.decode().is_none());
+        assert_eq!(decoder.buffered(), 3);
+
+        match encoder.encode(&Message::Ping, &mut [0; 1]) {
+            Err(Error::BufferTooSmall { len: 1, needed: 2 }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_codec() {
+        use bytes::BytesMut;
+        use tokio_util::codec::{Decoder, Encoder};
+        use crate::cand::CandCodec;
+
+        let mut codec = CandCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Message::VersionReply { major: 1, minor: 2 }, &mut buf).unwrap();
+        codec.encode(&Message::Ping, &mut buf).unwrap();
+        assert!(codec.encode(Message::FirmwareIdResponse("x".repeat(300)), &mut buf).is_err());
+        assert_eq!(&buf[..], &[2, 0x17, 1, 2, 0, 0x15]);
+
+        let mut input = buf.split_to(3);
+        assert!(codec.decode(&mut input).unwrap().is_none());
+        input.unsplit(buf);
+        match codec.decode(&mut input).unwrap() {
+            Some(Message::VersionReply { major: 1, minor: 2 }) => {}
+            other => panic!("unexpected message {:?}", other)
+        }
+        match codec.decode(&mut input).unwrap() {
+            Some(Message::Ping) => {}
+            other => panic!("unexpected message {:?}", other)
+        }
+        assert!(input.is_empty());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_codec_resync() {
+        use std::future::poll_fn;
+        use std::pin::Pin;
+        use futures_core::Stream;
+        use tokio_util::codec::FramedRead;
+        use crate::cand::CandCodec;
+
+        let input: &[u8] = &[3, 0x17, 1, 2, 3, 0, 0x15];
+        let mut framed = FramedRead::new(input, CandCodec::new_strict());
+        match poll_fn(|cx| Pin::new(&mut framed).poll_next(cx)).await {
+            Some(Ok(Message::Ping)) => {}
+            other => panic!("unexpected message {:?}", other)
+        }
+        assert!(poll_fn(|cx| Pin::new(&mut framed).poll_next(cx)).await.is_none());
+
+        let mut framed = FramedRead::new(input, CandCodec::new());
+        match poll_fn(|cx| Pin::new(&mut framed).poll_next(cx)).await {
+            Some(Ok(Message::Unknown { kind: 0x17, payload })) => assert_eq!(payload, &[1, 2, 3]),
+            other => panic!("unexpected message {:?}", other)
+        }
+        match poll_fn(|cx| Pin::new(&mut framed).poll_next(cx)).await {
+            Some(Ok(Message::Ping)) => {}
+            other => panic!("unexpected message {:?}", other)
+        }
+        assert!(poll_fn(|cx| Pin::new(&mut framed).poll_next(cx)).await.is_none());
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let filter = CanFilter {
+            masks: [0x1fffffff, 0x0000ff00],
+            filters: [0x123, 0x123, 0x2400, 0x2400, 0x2400, 0x2400]
+        };
+
+        assert!(filter.matches(0x123));
+        assert!(filter.matches(0x1a2b24ff));
+        assert!(!filter.matches(0x124));
+        assert!(CanFilter::accept_all().matches(0x1fffffff));
+    }
+}
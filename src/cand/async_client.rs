@@ -0,0 +1,733 @@
+use std::cmp;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time;
+use crate::can::CanPacket;
+use crate::cand::{read_packet_async, write_packet_to_cand_async, BusPower, Decoder, Message, DEFAULT_TIMEOUT, DEFAULT_TX_SETTLE};
+use crate::error::{Error, Result};
+use crate::transport::{Endpoint, Transport};
+
+/// Number of frames a subscriber may lag behind before it starts missing frames
+const FRAME_BUFFER: usize = 256;
+
+/// Buffer size of the in-process stream to a blocking transport
+const BRIDGE_BUFFER: usize = 4096;
+
+/// How often the thread receiving from a blocking transport checks whether the client is gone
+const BRIDGE_POLL: Duration = Duration::from_millis(100);
+
+/// Changes of the connection to cand, as seen by [AsyncCandClient::subscribe_events]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ConnectionEvent {
+    Connected,
+    /// Frames sent on the bus until the next [ConnectionEvent::Connected] are missed
+    Disconnected
+}
+
+/// Settings for [AsyncCandClient::connect_persistent] and [AsyncCandClient::open_persistent]
+#[derive(Debug, Copy, Clone)]
+pub struct ReconnectPolicy {
+    /// Interval between two pings. A ping that is not answered until the next one is due marks
+    /// the connection as dead.
+    pub keepalive: Duration,
+    /// Delay before the first reconnect attempt, doubled after every failed attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            keepalive: Duration::from_secs(5),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30)
+        }
+    }
+}
+
+/// A request waiting for its response in the connection task
+trait PendingQuery: Send {
+    /// The caller stopped waiting, e.g. because it timed out
+    fn is_closed(&self) -> bool;
+
+    /// Completes the query if `msg` answers it
+    fn complete(&mut self, msg: &Message) -> bool;
+
+    fn is_keepalive(&self) -> bool {
+        false
+    }
+}
+
+/// A ping sent by the connection task itself, queued like a user query so that its reply cannot
+/// complete a [AsyncCandClient::ping] sent later
+struct KeepalivePing;
+
+impl PendingQuery for KeepalivePing {
+    fn is_closed(&self) -> bool {
+        false
+    }
+
+    fn complete(&mut self, msg: &Message) -> bool {
+        matches!(msg, Message::Ping)
+    }
+
+    fn is_keepalive(&self) -> bool {
+        true
+    }
+}
+
+struct Query<T, F> {
+    matcher: F,
+    reply: Option<oneshot::Sender<T>>
+}
+
+impl<T, F> PendingQuery for Query<T, F> where T: Send, F: FnMut(&Message) -> Option<T> + Send {
+    fn is_closed(&self) -> bool {
+        match &self.reply {
+            Some(reply) => reply.is_closed(),
+            None => true
+        }
+    }
+
+    fn complete(&mut self, msg: &Message) -> bool {
+        match (self.matcher)(msg) {
+            Some(result) => {
+                if let Some(reply) = self.reply.take() {
+                    let _ = reply.send(result);
+                }
+                true
+            }
+            None => false
+        }
+    }
+}
+
+enum Command {
+    Send(Message, oneshot::Sender<Result<()>>),
+    Query(Message, Box<dyn PendingQuery>)
+}
+
+impl Command {
+    fn reject(self) {
+        // A dropped query reports Error::Disconnected to its caller
+        if let Command::Send(_, reply) = self {
+            let _ = reply.send(Err(Error::Disconnected));
+        }
+    }
+}
+
+/// Why a connection task stopped
+enum Exit {
+    /// All clients are gone
+    Closed,
+    Lost
+}
+
+/// Asynchronous client for a cand connection.
+///
+/// Any [Endpoint] can be opened. TCP and Unix sockets are used as async streams directly, other
+/// links, like serial ports or a [SimBus](crate::sim::SimBus), are bridged from their blocking
+/// [Transport] by two background threads.
+///
+/// The connection is owned by a background task, so the client can be cloned and used from many
+/// tasks at the same time. Any number of queries may be in flight concurrently, each response
+/// completes the oldest query waiting for it. Received frames are distributed to all
+/// subscribers.
+#[derive(Clone)]
+pub struct AsyncCandClient {
+    commands: mpsc::Sender<Command>,
+    frames: broadcast::Sender<CanPacket>,
+    events: broadcast::Sender<ConnectionEvent>,
+    timeout: Duration,
+    tx_settle: Duration,
+    tx_retries: usize
+}
+
+impl AsyncCandClient {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<AsyncCandClient> {
+        Ok(AsyncCandClient::new(TcpStream::connect(addr).await?))
+    }
+
+    pub async fn open(endpoint: &Endpoint) -> Result<AsyncCandClient> {
+        Ok(AsyncCandClient::new(connect_stream(endpoint).await?))
+    }
+
+    /// Spawns the connection task for a blocking `transport` on the current tokio runtime
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Result<AsyncCandClient> {
+        Ok(AsyncCandClient::new(bridge(transport)?))
+    }
+
+    /// Spawns the connection task for `stream` on the current tokio runtime
+    pub fn new<S>(stream: S) -> AsyncCandClient
+        where S: AsyncRead + AsyncWrite + Send + 'static {
+        let (client, mut commands) = AsyncCandClient::with_channels();
+        let frames = client.frames.clone();
+        let events = client.events.clone();
+
+        tokio::spawn(async move {
+            run_connection(stream, &mut commands, &frames, None).await;
+            let _ = events.send(ConnectionEvent::Disconnected);
+        });
+
+        client
+    }
+
+    /// Spawns a task on the current tokio runtime that keeps a connection to `addr` alive.
+    ///
+    /// The connection is checked by regular pings and reestablished with exponential backoff
+    /// whenever it is lost. Requests made while disconnected fail with [Error::Disconnected].
+    pub fn connect_persistent<A>(addr: A, policy: ReconnectPolicy) -> AsyncCandClient
+        where A: ToSocketAddrs + Clone + Send + Sync + 'static {
+        let connect = move || {
+            let addr = addr.clone();
+            async move { Ok(TcpStream::connect(addr).await?) }
+        };
+        AsyncCandClient::persistent(connect, policy)
+    }
+
+    /// Like [AsyncCandClient::connect_persistent], for any [Endpoint]
+    pub fn open_persistent(endpoint: Endpoint, policy: ReconnectPolicy) -> AsyncCandClient {
+        let connect = move || {
+            let endpoint = endpoint.clone();
+            async move { connect_stream(&endpoint).await }
+        };
+        AsyncCandClient::persistent(connect, policy)
+    }
+
+    fn persistent<C, F, S>(connect: C, policy: ReconnectPolicy) -> AsyncCandClient
+        where C: FnMut() -> F + Send + 'static,
+              F: Future<Output = Result<S>> + Send + 'static,
+              S: AsyncRead + AsyncWrite + Send + 'static {
+        let (client, commands) = AsyncCandClient::with_channels();
+        let frames = client.frames.clone();
+        let events = client.events.clone();
+
+        tokio::spawn(run_persistent(connect, policy, commands, frames, events));
+
+        client
+    }
+
+    /// Creates a client together with the receiving end of its command channel, which is handed
+    /// to the connection task
+    fn with_channels() -> (AsyncCandClient, mpsc::Receiver<Command>) {
+        let (commands, command_rx) = mpsc::channel(32);
+        let (frames, _) = broadcast::channel(FRAME_BUFFER);
+        let (events, _) = broadcast::channel(16);
+
+        let client = AsyncCandClient {
+            commands,
+            frames,
+            events,
+            timeout: DEFAULT_TIMEOUT,
+            tx_settle: DEFAULT_TX_SETTLE,
+            tx_retries: 3
+        };
+        (client, command_rx)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Sets the time to wait for the answer to a request
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how long [AsyncCandClient::send_frame] waits for a TX failure notification and how
+    /// often a failed frame is sent again
+    pub fn set_tx_settle(&mut self, settle: Duration, retries: usize) {
+        self.tx_settle = settle;
+        self.tx_retries = retries;
+    }
+
+    /// Returns a receiver for all frames received from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<CanPacket> {
+        self.frames.subscribe()
+    }
+
+    /// Returns a receiver for all connection state changes from now on
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn send(&self, msg: Message) -> Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.commands.send(Command::Send(msg, reply)).await
+            .map_err(|_| Error::Disconnected)?;
+        result.await.map_err(|_| Error::Disconnected)?
+    }
+
+    /// Sends `request` and waits for the first message `matcher` returns a value for.
+    ///
+    /// # Errors
+    /// [Error::Timeout] if no matching message arrives within the timeout,
+    /// [Error::Disconnected] if the connection is closed while waiting
+    pub async fn query<T, F>(&self, request: Message, matcher: F) -> Result<T>
+        where T: Send + 'static, F: FnMut(&Message) -> Option<T> + Send + 'static {
+        self.query_timeout(request, matcher, self.timeout).await
+    }
+
+    async fn query_timeout<T, F>(&self, request: Message, matcher: F, timeout: Duration) -> Result<T>
+        where T: Send + 'static, F: FnMut(&Message) -> Option<T> + Send + 'static {
+        let (reply, result) = oneshot::channel();
+        let query = Query {
+            matcher,
+            reply: Some(reply)
+        };
+        self.commands.send(Command::Query(request, Box::new(query))).await
+            .map_err(|_| Error::Disconnected)?;
+
+        match time::timeout(timeout, result).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) => Err(Error::Disconnected),
+            Err(_) => Err(Error::Timeout)
+        }
+    }
+
+    /// Pings the gateway and returns the round trip time
+    pub async fn ping(&self) -> Result<Duration> {
+        let start = time::Instant::now();
+        self.query(Message::Ping, |msg| match msg {
+            Message::Ping => Some(()),
+            _ => None
+        }).await?;
+        Ok(start.elapsed())
+    }
+
+    /// Returns the major and minor protocol version of the gateway
+    pub async fn version(&self) -> Result<(u8, u8)> {
+        self.query(Message::VersionRequest, |msg| match msg {
+            Message::VersionReply { major, minor } => Some((*major, *minor)),
+            _ => None
+        }).await
+    }
+
+    pub async fn firmware_id(&self) -> Result<String> {
+        self.query(Message::FirmwareIdRequest, |msg| match msg {
+            Message::FirmwareIdResponse(id) => Some(id.clone()),
+            _ => None
+        }).await
+    }
+
+    pub async fn bus_power(&self) -> Result<BusPower> {
+        self.query(Message::BusPowerRequest, |msg| match msg {
+            Message::BusPowerResponse { v, i, reference, gnd } => Some(BusPower {
+                v: *v,
+                i: *i,
+                reference: *reference,
+                gnd: *gnd
+            }),
+            _ => None
+        }).await
+    }
+
+    /// Sends `frame` and waits for a TX failure notification, sending the frame again if it
+    /// failed.
+    ///
+    /// # Errors
+    /// [Error::TxFailed] if the frame still failed after all retries
+    pub async fn send_frame(&self, frame: CanPacket) -> Result<()> {
+        for _ in 0..=self.tx_retries {
+            let failed = frame.clone();
+            let result = self.query_timeout(Message::Frame(frame.clone()), move |msg| match msg {
+                Message::TxFailed(packet) if *packet == failed => Some(()),
+                _ => None
+            }, self.tx_settle).await;
+
+            match result {
+                Ok(()) => {}
+                Err(Error::Timeout) => return Ok(()),
+                Err(e) => return Err(e)
+            }
+        }
+        Err(Error::TxFailed(frame))
+    }
+}
+
+/// Byte streams the connection task can run on
+trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for S {}
+
+async fn connect_stream(endpoint: &Endpoint) -> Result<Box<dyn AsyncStream>> {
+    match endpoint {
+        Endpoint::Tcp { host, port } => Ok(Box::new(TcpStream::connect((host.as_str(), *port)).await?)),
+        #[cfg(unix)]
+        Endpoint::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+        _ => Ok(Box::new(bridge(endpoint.connect()?)?))
+    }
+}
+
+/// Passes messages between a blocking `transport` and the returned stream in two threads.
+///
+/// The stream ends when the transport is closed, the transport is dropped once the stream is.
+fn bridge<T: Transport + 'static>(transport: T) -> Result<DuplexStream> {
+    let (stream, bridged) = io::duplex(BRIDGE_BUFFER);
+    let (mut read, mut write) = io::split(bridged);
+    let handle = Handle::current();
+    let closed = Arc::new(AtomicBool::new(false));
+
+    let mut sender = transport.try_clone()?;
+    let sender_handle = handle.clone();
+    let sender_closed = closed.clone();
+    thread::spawn(move || {
+        while let Ok(Some(msg)) = sender_handle.block_on(read_packet_async(&mut read)) {
+            match sender.send(&msg) {
+                Ok(()) => {}
+                Err(e @ Error::IOError(_)) | Err(e @ Error::Disconnected) => {
+                    log::warn!("Sending to cand failed: {}", e);
+                    break;
+                }
+                Err(e) => log::warn!("Dropping message to cand: {}", e)
+            }
+        }
+        sender_closed.store(true, Ordering::Relaxed);
+    });
+
+    let mut receiver = transport;
+    thread::spawn(move || {
+        loop {
+            let msg = match receiver.recv_timeout(Some(BRIDGE_POLL)) {
+                Ok(Some(msg)) => msg,
+                Err(Error::Timeout) if !closed.load(Ordering::Relaxed) => continue,
+                Ok(None) | Err(Error::Timeout) | Err(Error::IOError(_)) | Err(Error::Disconnected) => break,
+                Err(e) => {
+                    log::warn!("Dropping message from cand: {}", e);
+                    continue;
+                }
+            };
+            match handle.block_on(write_packet_to_cand_async(&mut write, &msg)) {
+                Ok(()) => {}
+                Err(Error::IOError(_)) => break,
+                Err(e) => log::warn!("Dropping message from cand: {}", e)
+            }
+        }
+        // Lets the connection task see the end of the stream
+        let _ = handle.block_on(write.shutdown());
+    });
+
+    Ok(stream)
+}
+
+async fn run_persistent<C, F, S>(
+    mut connect: C,
+    policy: ReconnectPolicy,
+    mut commands: mpsc::Receiver<Command>,
+    frames: broadcast::Sender<CanPacket>,
+    events: broadcast::Sender<ConnectionEvent>
+) where C: FnMut() -> F, F: Future<Output = Result<S>>, S: AsyncRead + AsyncWrite {
+    let mut backoff = policy.initial_backoff;
+    loop {
+        match connect().await {
+            Ok(stream) => {
+                backoff = policy.initial_backoff;
+                let _ = events.send(ConnectionEvent::Connected);
+                let exit = run_connection(stream, &mut commands, &frames, Some(policy.keepalive)).await;
+                let _ = events.send(ConnectionEvent::Disconnected);
+                if let Exit::Closed = exit {
+                    return;
+                }
+            }
+            Err(e) => log::warn!("Connecting to cand failed: {}", e)
+        }
+
+        let retry = time::sleep(backoff);
+        tokio::pin!(retry);
+        loop {
+            tokio::select! {
+                _ = &mut retry => break,
+                command = commands.recv() => match command {
+                    Some(command) => command.reject(),
+                    None => return
+                }
+            }
+        }
+        backoff = cmp::min(backoff * 2, policy.max_backoff);
+    }
+}
+
+async fn run_connection<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    commands: &mut mpsc::Receiver<Command>,
+    frames: &broadcast::Sender<CanPacket>,
+    keepalive: Option<Duration>
+) -> Exit {
+    let (mut read, mut write) = io::split(stream);
+    let mut decoder = Decoder::new();
+    let mut pending: Vec<Box<dyn PendingQuery>> = Vec::new();
+    let mut buf = [0; 512];
+
+    // Without keepalive the timer is never polled
+    let mut keepalive_timer = time::interval(keepalive.unwrap_or(Duration::from_secs(3600)));
+    keepalive_timer.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    keepalive_timer.tick().await;
+    let mut ping_outstanding = false;
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => {
+                let write_failed = match command {
+                    Some(Command::Send(msg, reply)) => {
+                        let result = write_message(&mut write, &msg).await;
+                        let failed = result.is_err();
+                        let _ = reply.send(result);
+                        failed
+                    }
+                    Some(Command::Query(msg, query)) => {
+                        pending.push(query);
+                        write_message(&mut write, &msg).await.is_err()
+                    }
+                    None => return Exit::Closed
+                };
+                if write_failed {
+                    return Exit::Lost;
+                }
+            }
+            read = read.read(&mut buf) => {
+                let len = match read {
+                    Ok(0) => return Exit::Lost,
+                    Ok(len) => len,
+                    Err(e) => {
+                        log::warn!("Reading from cand failed: {}", e);
+                        return Exit::Lost;
+                    }
+                };
+
+                for msg in decoder.feed(&buf[..len]) {
+                    match msg {
+                        Ok(msg) => {
+                            if dispatch(&mut pending, frames, msg).is_some_and(|query| query.is_keepalive()) {
+                                ping_outstanding = false;
+                            }
+                        }
+                        Err(e) => log::warn!("Could not decode message from cand: {}", e)
+                    }
+                }
+            }
+            _ = keepalive_timer.tick(), if keepalive.is_some() => {
+                if ping_outstanding {
+                    log::warn!("cand did not answer ping, reconnecting");
+                    return Exit::Lost;
+                }
+                pending.push(Box::new(KeepalivePing));
+                if write_message(&mut write, &Message::Ping).await.is_err() {
+                    return Exit::Lost;
+                }
+                ping_outstanding = true;
+            }
+        }
+    }
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(write: &mut W, msg: &Message) -> Result<()> {
+    write_packet_to_cand_async(write, msg).await
+}
+
+/// Completes the oldest query answered by `msg` and returns it
+fn dispatch(
+    pending: &mut Vec<Box<dyn PendingQuery>>,
+    frames: &broadcast::Sender<CanPacket>,
+    msg: Message
+) -> Option<Box<dyn PendingQuery>> {
+    pending.retain(|query| !query.is_closed());
+    let completed = pending.iter_mut()
+        .position(|query| query.complete(&msg))
+        .map(|idx| pending.remove(idx));
+
+    if let Message::Frame(frame) = msg {
+        // Nobody listening is not an error
+        let _ = frames.send(frame);
+    }
+    completed
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use crate::can::{CanAddr, CanPacket};
+    use crate::cand::{read_packet_async, write_packet_to_cand_async, AsyncCandClient, ConnectionEvent, Message, ReconnectPolicy};
+    use crate::error::Error;
+    use crate::sim::SimBus;
+    use crate::transport::Endpoint;
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // The first connection never answers, the second one does
+            let (mut dead, _) = listener.accept().await.unwrap();
+            let (mut sock, _) = listener.accept().await.unwrap();
+            drop(read_packet_async(&mut dead).await);
+            while let Some(msg) = read_packet_async(&mut sock).await.unwrap() {
+                let reply = match msg {
+                    Message::Ping => Message::Ping,
+                    Message::VersionRequest => Message::VersionReply { major: 1, minor: 3 },
+                    _ => continue
+                };
+                write_packet_to_cand_async(&mut sock, &reply).await.unwrap();
+            }
+        });
+
+        let policy = ReconnectPolicy {
+            keepalive: Duration::from_millis(100),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100)
+        };
+        let client = AsyncCandClient::connect_persistent(addr, policy);
+        let mut events = client.subscribe_events();
+
+        assert_eq!(events.recv().await.unwrap(), ConnectionEvent::Connected);
+        assert_eq!(events.recv().await.unwrap(), ConnectionEvent::Disconnected);
+        assert_eq!(events.recv().await.unwrap(), ConnectionEvent::Connected);
+        assert_eq!(client.version().await.unwrap(), (1, 3));
+
+        // Pings are answered, so the connection stays up
+        match tokio::time::timeout(Duration::from_millis(500), events.recv()).await {
+            Err(_) => {}
+            other => panic!("unexpected event {:?}", other)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_ping_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut pings = 0;
+            while let Some(msg) = read_packet_async(&mut sock).await.unwrap() {
+                if msg != Message::Ping {
+                    continue;
+                }
+                pings += 1;
+                // Answer the keepalive only once the user's ping arrived, and the user's ping later
+                if pings == 2 {
+                    write_packet_to_cand_async(&mut sock, &Message::Ping).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
+                if pings >= 2 {
+                    write_packet_to_cand_async(&mut sock, &Message::Ping).await.unwrap();
+                }
+            }
+        });
+
+        let policy = ReconnectPolicy {
+            keepalive: Duration::from_millis(300),
+            ..ReconnectPolicy::default()
+        };
+        let endpoint = Endpoint::Tcp { host: addr.ip().to_string(), port: addr.port() };
+        let client = AsyncCandClient::open_persistent(endpoint, policy);
+        let mut events = client.subscribe_events();
+        assert_eq!(events.recv().await.unwrap(), ConnectionEvent::Connected);
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(client.ping().await.unwrap() >= Duration::from_millis(150));
+        match tokio::time::timeout(Duration::from_millis(700), events.recv()).await {
+            Err(_) => {}
+            other => panic!("unexpected event {:?}", other)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_queries() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let frame = CanPacket::new(CanAddr::new(0x42, 1).unwrap(), CanAddr::new(0, 0x23).unwrap(), vec![1]).unwrap();
+        let broadcast = frame.clone();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut requests = Vec::new();
+            while let Some(msg) = read_packet_async(&mut sock).await.unwrap() {
+                requests.push(msg);
+                if requests.len() < 3 {
+                    continue;
+                }
+
+                // Answer out of order to check the correlation
+                write_packet_to_cand_async(&mut sock, &Message::Frame(broadcast.clone())).await.unwrap();
+                for request in requests.drain(..).rev() {
+                    let reply = match request {
+                        Message::VersionRequest => Message::VersionReply { major: 1, minor: 3 },
+                        Message::Ping => Message::Ping,
+                        Message::BusPowerRequest => Message::BusPowerResponse { v: 1, i: 2, reference: 3, gnd: 4 },
+                        _ => continue
+                    };
+                    write_packet_to_cand_async(&mut sock, &reply).await.unwrap();
+                }
+            }
+        });
+
+        let mut client = AsyncCandClient::connect(addr).await.unwrap();
+        client.set_timeout(Duration::from_millis(500));
+        let mut frames = client.subscribe();
+        let mut other_frames = client.subscribe();
+
+        let (version, ping, power) = tokio::join!(client.version(), client.ping(), client.bus_power());
+        assert_eq!(version.unwrap(), (1, 3));
+        ping.unwrap();
+        assert_eq!(power.unwrap().gnd, 4);
+        assert_eq!(frames.recv().await.unwrap(), frame);
+        assert_eq!(other_frames.recv().await.unwrap(), frame);
+
+        match client.firmware_id().await {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transport() {
+        let bus = SimBus::new();
+        bus.set_version(2, 1);
+        // Answers every frame by echoing it back to the sender
+        bus.add_node(0x24, |frame| vec![CanPacket {
+            src: frame.dest,
+            dest: frame.src,
+            payload: frame.payload.clone()
+        }]);
+
+        let mut client = bus.connect_async();
+        client.set_tx_settle(Duration::from_millis(10), 0);
+        let mut frames = client.subscribe();
+        assert_eq!(client.version().await.unwrap(), (2, 1));
+        client.ping().await.unwrap();
+
+        let request = CanPacket::new(CanAddr::new(0, 0x23).unwrap(), CanAddr::new(0x24, 2).unwrap(), vec![1, 2]).unwrap();
+        client.send_frame(request.clone()).await.unwrap();
+        let response = frames.recv().await.unwrap();
+        assert_eq!((response.src, response.dest), (request.dest, request.src));
+        assert_eq!(bus.history(), vec![request, response]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_open_unix() {
+        let path = std::env::temp_dir().join(format!("labctl-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            while let Some(msg) = read_packet_async(&mut sock).await.unwrap() {
+                if msg == Message::VersionRequest {
+                    write_packet_to_cand_async(&mut sock, &Message::VersionReply { major: 1, minor: 3 }).await.unwrap();
+                }
+            }
+        });
+
+        let client = AsyncCandClient::open(&Endpoint::Unix(path.clone())).await.unwrap();
+        assert_eq!(client.version().await.unwrap(), (1, 3));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
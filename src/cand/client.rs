@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use crate::can::CanPacket;
+use crate::cand::Message;
+use crate::error::{Error, Result};
+use crate::transport::{Endpoint, TcpTransport, Transport};
+
+/// Default time to wait for the answer to a request
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Default time to wait for a TX failure notification after sending a frame
+pub const DEFAULT_TX_SETTLE: Duration = Duration::from_millis(30);
+
+/// Bus power measurement of the gateway in raw ADC values
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct BusPower {
+    pub v: u16,
+    pub i: u16,
+    pub reference: u16,
+    pub gnd: u16
+}
+
+impl BusPower {
+    /// Bus voltage in volts
+    pub fn voltage(&self) -> f64 {
+        let uadc = self.v as f64 * (5f64 / 1023f64);
+        (uadc * 3_700f64) / 1_000f64
+    }
+
+    /// Bus current in amperes
+    pub fn current(&self) -> f64 {
+        let iadc = self.i as f64 * (5f64 / 1023f64);
+        iadc / (10f64 * 0.01)
+    }
+}
+
+/// Blocking client for a cand connection or any other [Transport].
+///
+/// Requests wait for their matching response up to the configured timeout. Messages that
+/// arrive in the meantime are queued and returned by [CandClient::recv] later on.
+pub struct CandClient {
+    transport: Box<dyn Transport>,
+    queue: VecDeque<Message>,
+    timeout: Duration,
+    tx_settle: Duration,
+    tx_retries: usize
+}
+
+impl CandClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<CandClient> {
+        Ok(CandClient::new(TcpTransport::new(TcpStream::connect(addr)?)))
+    }
+
+    /// Connects to `endpoint`, e.g. a cand via TCP or a gateway on a serial port
+    pub fn open(endpoint: &Endpoint) -> Result<CandClient> {
+        Ok(CandClient::new(endpoint.connect()?))
+    }
+
+    pub fn new<T: Transport + 'static>(transport: T) -> CandClient {
+        CandClient {
+            transport: Box::new(transport),
+            queue: VecDeque::new(),
+            timeout: DEFAULT_TIMEOUT,
+            tx_settle: DEFAULT_TX_SETTLE,
+            tx_retries: 3
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Sets the time to wait for the answer to a request
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how long [CandClient::send_frame] waits for a TX failure notification and how often a
+    /// failed frame is sent again
+    pub fn set_tx_settle(&mut self, settle: Duration, retries: usize) {
+        self.tx_settle = settle;
+        self.tx_retries = retries;
+    }
+
+    /// See [Transport::set_strict]
+    pub fn set_strict(&mut self, strict: bool) {
+        self.transport.set_strict(strict);
+    }
+
+    pub fn send(&mut self, msg: &Message) -> Result<()> {
+        self.transport.send(msg)
+    }
+
+    /// Returns the next queued or received message, `None` once the connection was closed
+    pub fn recv(&mut self) -> Result<Option<Message>> {
+        match self.queue.pop_front() {
+            Some(msg) => Ok(Some(msg)),
+            None => self.read_message(None)
+        }
+    }
+
+    /// Like [CandClient::recv], but fails with [Error::Timeout] if nothing arrives in time
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Message>> {
+        match self.queue.pop_front() {
+            Some(msg) => Ok(Some(msg)),
+            None => self.read_message(Some(Instant::now() + timeout))
+        }
+    }
+
+    /// Sends `request` and waits for the first message `matcher` returns a value for.
+    ///
+    /// # Errors
+    /// [Error::Timeout] if no matching message arrives within the timeout,
+    /// [Error::Disconnected] if the connection is closed while waiting
+    pub fn query<T, F>(&mut self, request: &Message, mut matcher: F) -> Result<T>
+        where F: FnMut(&Message) -> Option<T> {
+        self.send(request)?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            match self.read_message(Some(deadline))? {
+                Some(msg) => match matcher(&msg) {
+                    Some(result) => return Ok(result),
+                    None => self.queue.push_back(msg)
+                },
+                None => return Err(Error::Disconnected)
+            }
+        }
+    }
+
+    /// Pings the gateway and returns the round trip time
+    pub fn ping(&mut self) -> Result<Duration> {
+        let start = Instant::now();
+        self.query(&Message::Ping, |msg| match msg {
+            Message::Ping => Some(()),
+            _ => None
+        })?;
+        Ok(start.elapsed())
+    }
+
+    /// Returns the major and minor protocol version of the gateway
+    pub fn version(&mut self) -> Result<(u8, u8)> {
+        self.query(&Message::VersionRequest, |msg| match msg {
+            Message::VersionReply { major, minor } => Some((*major, *minor)),
+            _ => None
+        })
+    }
+
+    pub fn firmware_id(&mut self) -> Result<String> {
+        self.query(&Message::FirmwareIdRequest, |msg| match msg {
+            Message::FirmwareIdResponse(id) => Some(id.clone()),
+            _ => None
+        })
+    }
+
+    pub fn bus_power(&mut self) -> Result<BusPower> {
+        self.query(&Message::BusPowerRequest, |msg| match msg {
+            Message::BusPowerResponse { v, i, reference, gnd } => Some(BusPower {
+                v: *v,
+                i: *i,
+                reference: *reference,
+                gnd: *gnd
+            }),
+            _ => None
+        })
+    }
+
+    /// Sends `frame` and waits for a TX failure notification, sending the frame again if it
+    /// failed.
+    ///
+    /// # Errors
+    /// [Error::TxFailed] if the frame still failed after all retries
+    pub fn send_frame(&mut self, frame: &CanPacket) -> Result<()> {
+        let mut attempt = 0;
+        'send: loop {
+            self.send(&Message::Frame(frame.clone()))?;
+
+            let deadline = Instant::now() + self.tx_settle;
+            loop {
+                match self.read_message(Some(deadline)) {
+                    Ok(Some(Message::TxFailed(failed))) if failed == *frame => {
+                        if attempt == self.tx_retries {
+                            return Err(Error::TxFailed(failed));
+                        }
+                        attempt += 1;
+                        continue 'send;
+                    }
+                    Ok(Some(msg)) => self.queue.push_back(msg),
+                    Ok(None) => return Err(Error::Disconnected),
+                    Err(Error::Timeout) => return Ok(()),
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+    }
+
+    /// Waits for the next message until the connection was closed or `deadline` passed
+    fn read_message(&mut self, deadline: Option<Instant>) -> Result<Option<Message>> {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(Error::Timeout);
+                }
+                Some(deadline - now)
+            }
+            None => None
+        };
+        self.transport.recv_timeout(timeout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use crate::can::{CanAddr, CanPacket};
+    use crate::cand::{read_packet, write_packet_to_cand, CandClient, Message};
+    use crate::error::Error;
+
+    #[test]
+    fn test_queries() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let frame = CanPacket::new(CanAddr::new(0x42, 1).unwrap(), CanAddr::new(0, 0x23).unwrap(), vec![1]).unwrap();
+        let unrelated = frame.clone();
+        let gateway = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            while let Some(msg) = read_packet(&mut sock).unwrap() {
+                let reply = match msg {
+                    Message::VersionRequest => Message::VersionReply { major: 1, minor: 3 },
+                    Message::FirmwareIdRequest => Message::FirmwareIdResponse("gateway".to_string()),
+                    Message::Ping => Message::Ping,
+                    _ => continue
+                };
+                write_packet_to_cand(&mut sock, &Message::Frame(unrelated.clone())).unwrap();
+                write_packet_to_cand(&mut sock, &reply).unwrap();
+            }
+        });
+
+        let mut client = CandClient::connect(addr).unwrap();
+        client.set_timeout(Duration::from_millis(200));
+        assert_eq!(client.version().unwrap(), (1, 3));
+        assert_eq!(client.firmware_id().unwrap(), "gateway");
+        client.ping().unwrap();
+        match client.bus_power() {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+
+        for _ in 0..3 {
+            match client.recv_timeout(Duration::from_millis(200)).unwrap() {
+                Some(Message::Frame(received)) => assert_eq!(received, frame),
+                other => panic!("unexpected message {:?}", other)
+            }
+        }
+        drop(client);
+        gateway.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_frame_retries() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gateway = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut sent = 0;
+            while let Some(Message::Frame(frame)) = read_packet(&mut sock).unwrap() {
+                sent += 1;
+                if sent < 3 {
+                    write_packet_to_cand(&mut sock, &Message::TxFailed(frame)).unwrap();
+                }
+                write_packet_to_cand(&mut sock, &Message::Ping).unwrap();
+            }
+            sent
+        });
+
+        let frame = CanPacket::new(CanAddr::new(0, 0x23).unwrap(), CanAddr::new(0x42, 1).unwrap(), vec![1, 2]).unwrap();
+        let mut client = CandClient::connect(addr).unwrap();
+        client.set_tx_settle(Duration::from_millis(100), 1);
+        match client.send_frame(&frame) {
+            Err(Error::TxFailed(failed)) => assert_eq!(failed, frame),
+            other => panic!("unexpected result {:?}", other)
+        }
+        client.send_frame(&frame).unwrap();
+        drop(client);
+
+        assert_eq!(gateway.join().unwrap(), 3);
+    }
+}
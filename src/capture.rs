@@ -0,0 +1,205 @@
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::cand::{read_packet, write_packet_to_cand, Message};
+use crate::error::{Error, Result};
+
+/// Magic bytes at the start of every capture file, followed by the format version
+const MAGIC: &[u8; 6] = b"LABCAP";
+
+const VERSION: u8 = 1;
+
+/// A message captured at `time` after the start of the capture
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Record {
+    pub time: Duration,
+    pub message: Message
+}
+
+/// Writes messages to a capture file.
+///
+/// A capture starts with a header of the magic bytes, the format version and the start time in
+/// microseconds since the unix epoch (u64 LE). Every record consists of the microseconds since
+/// the previous record as unsigned LEB128 followed by the message in cand framing.
+pub struct CaptureWriter<W: Write> {
+    write: W,
+    /// Time of the previous record in microseconds
+    last: u64
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Writes the header for a capture started at `start`
+    pub fn new(mut write: W, start: SystemTime) -> Result<CaptureWriter<W>> {
+        let start = start.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write.write_all(MAGIC)?;
+        write.write_u8(VERSION)?;
+        write.write_u64::<LittleEndian>(start.as_micros() as u64)?;
+        Ok(CaptureWriter {
+            write,
+            last: 0
+        })
+    }
+
+    /// Appends `message` received at `time` after the start of the capture.
+    ///
+    /// Records must be written in order, earlier times are recorded as the time of the previous
+    /// record.
+    ///
+    /// # Errors
+    /// [Error::PayloadTooLarge] if `message` cannot be encoded, nothing is written then
+    pub fn write(&mut self, time: Duration, message: &Message) -> Result<()> {
+        let mut buf = Vec::new();
+        write_packet_to_cand(&mut buf, message)?;
+        let delta = (time.as_micros() as u64).saturating_sub(self.last);
+        write_varint(&mut self.write, delta)?;
+        self.write.write_all(&buf)?;
+        self.last += delta;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.write.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.write
+    }
+}
+
+/// Reads the records of a capture file written by [CaptureWriter]
+pub struct CaptureReader<R: Read> {
+    read: R,
+    start: SystemTime,
+    time: Duration
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Reads the header
+    ///
+    /// # Errors
+    /// [Error::InvalidCapture] if `read` does not contain a capture in a supported version
+    pub fn new(mut read: R) -> Result<CaptureReader<R>> {
+        let mut magic = [0; 6];
+        read.read_exact(&mut magic)?;
+        if &magic != MAGIC || read.read_u8()? != VERSION {
+            return Err(Error::InvalidCapture);
+        }
+        let start = UNIX_EPOCH + Duration::from_micros(read.read_u64::<LittleEndian>()?);
+        Ok(CaptureReader {
+            read,
+            start,
+            time: Duration::from_secs(0)
+        })
+    }
+
+    /// The time the capture was started
+    pub fn start(&self) -> SystemTime {
+        self.start
+    }
+
+    /// Returns the next record, `None` at the end of the capture
+    pub fn read(&mut self) -> Result<Option<Record>> {
+        let delta = match read_varint(&mut self.read)? {
+            Some(delta) => delta,
+            None => return Ok(None)
+        };
+        self.time += Duration::from_micros(delta);
+
+        match read_packet(&mut self.read)? {
+            Some(message) => Ok(Some(Record {
+                time: self.time,
+                message
+            })),
+            None => Err(Error::InvalidCapture)
+        }
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        self.read().transpose()
+    }
+}
+
+fn write_varint<W: Write>(write: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return write.write_u8(byte);
+        }
+        write.write_u8(byte | 0x80)?;
+    }
+}
+
+/// Reads an unsigned LEB128 value, `None` if `read` ends before its first byte
+fn read_varint<R: Read>(read: &mut R) -> Result<Option<u64>> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = match read.read_u8() {
+            Ok(byte) => byte,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            Err(e) => return Err(e.into())
+        };
+        if shift > 63 {
+            return Err(Error::InvalidCapture);
+        }
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::can::{CanAddr, CanPacket};
+    use crate::cand::Message;
+    use crate::capture::{CaptureReader, CaptureWriter, Record};
+    use crate::error::Error;
+
+    #[test]
+    fn test_capture() {
+        let start = UNIX_EPOCH + Duration::from_micros(1_600_000_000_123_456);
+        let frame = CanPacket::new(CanAddr::new(0x24, 2).unwrap(), CanAddr::new(0, 0x23).unwrap(), vec![1, 2, 3]).unwrap();
+        let records = vec![
+            Record { time: Duration::from_micros(5), message: Message::Frame(frame) },
+            Record { time: Duration::from_millis(300), message: Message::VersionReply { major: 1, minor: 3 } },
+            Record { time: Duration::from_secs(7200), message: Message::Ping }
+        ];
+
+        let mut writer = CaptureWriter::new(Vec::new(), start).unwrap();
+        for record in &records {
+            writer.write(record.time, &record.message).unwrap();
+        }
+        let buf = writer.into_inner();
+
+        let reader = CaptureReader::new(&buf[..]).unwrap();
+        assert_eq!(reader.start(), start);
+        assert_eq!(reader.map(|record| record.unwrap()).collect::<Vec<_>>(), records);
+
+        let mut writer = CaptureWriter::new(Vec::new(), start).unwrap();
+        let addr = CanAddr::new(0x24, 2).unwrap();
+        let oversize = CanPacket { src: addr, dest: addr, payload: vec![0; 12] };
+        match writer.write(Duration::from_micros(5), &Message::Frame(oversize)) {
+            Err(Error::PayloadTooLarge { len: 12, max: 8 }) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+        writer.write(Duration::from_micros(7), &Message::Ping).unwrap();
+        let buf = writer.into_inner();
+        let mut reader = CaptureReader::new(&buf[..]).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), Record { time: Duration::from_micros(7), message: Message::Ping });
+        assert!(reader.next().is_none());
+
+        match CaptureReader::new(&b"candump"[..]) {
+            Err(Error::InvalidCapture) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ()))
+        }
+    }
+}
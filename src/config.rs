@@ -0,0 +1,259 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::address_book::AddressBook;
+use crate::can::CanAddr;
+use crate::cand::{CandClient, DEFAULT_TX_SETTLE};
+use crate::error::{Error, Result};
+use crate::transport::{Endpoint, DEFAULT_PORT};
+
+/// System wide config file, overridden by the user's
+pub const SYSTEM_CONFIG: &str = "/etc/labctl.toml";
+
+/// Settings of labctl, merged from config files, `LABCTL_*` environment variables and command
+/// line flags.
+///
+/// ```toml
+/// host = "10.0.1.4"
+/// source = "00:23"
+/// timeout = 2.0
+///
+/// [addresses]
+/// borg-main = "24:23"
+/// ```
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Endpoint URL, takes precedence over host of the same layer
+    pub connect: Option<Endpoint>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// Source address of frames sent by labctl, or its name from the address book
+    pub source: Option<String>,
+    /// Seconds to wait for the answer to a request
+    pub timeout: Option<f64>,
+    /// Seconds to wait for a TX failure notification after every frame
+    pub tx_settle: Option<f64>,
+    pub tx_retries: Option<usize>,
+    pub addresses: AddressBook
+}
+
+impl Config {
+    pub fn from_toml(s: &str) -> Result<Config> {
+        toml::from_str(s).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+
+    /// Reads the config file at `path`, `None` if it does not exist
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Option<Config>> {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(s) => Config::from_toml(&s)
+                .map(Some)
+                .map_err(|e| Error::InvalidConfig(format!("{}: {}", path.display(), e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    /// Reads `LABCTL_CONNECT`, `LABCTL_HOST`, `LABCTL_PORT`, `LABCTL_SOURCE`, `LABCTL_TIMEOUT`,
+    /// `LABCTL_TX_SETTLE` and `LABCTL_TX_RETRIES` from `vars`
+    pub fn from_env_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> Result<Config> {
+        let mut config = Config::default();
+        for (name, value) in vars {
+            match name.as_str() {
+                "LABCTL_CONNECT" => config.connect = Some(parse_var(&name, &value)?),
+                "LABCTL_HOST" => config.host = Some(value),
+                "LABCTL_PORT" => config.port = Some(parse_var(&name, &value)?),
+                "LABCTL_SOURCE" => config.source = Some(value),
+                "LABCTL_TIMEOUT" => config.timeout = Some(parse_var(&name, &value)?),
+                "LABCTL_TX_SETTLE" => config.tx_settle = Some(parse_var(&name, &value)?),
+                "LABCTL_TX_RETRIES" => config.tx_retries = Some(parse_var(&name, &value)?),
+                _ => {}
+            }
+        }
+        Ok(config)
+    }
+
+    /// Merges the system config, the user config and the environment, in this order
+    pub fn load() -> Result<Config> {
+        let mut config = Config::from_file(SYSTEM_CONFIG)?.unwrap_or_default();
+        if let Some(user) = user_config_path() {
+            if let Some(user) = Config::from_file(user)? {
+                config.merge(user)?;
+            }
+        }
+        config.merge(Config::from_env_vars(env::vars())?)?;
+        Ok(config)
+    }
+
+    /// Overrides all settings `other` has, keeping the addresses of both.
+    ///
+    /// A port without a host overrides the port of a TCP endpoint, given earlier or in `other`.
+    ///
+    /// # Errors
+    /// [Error::InvalidConfig] if `other` has a port without a host but the endpoint is not TCP
+    pub fn merge(&mut self, mut other: Config) -> Result<()> {
+        // Host and connect are two ways to say where to connect to
+        if other.connect.is_some() {
+            self.host = None;
+            self.port = None;
+        }
+        if other.host.is_some() {
+            self.connect = None;
+        }
+        if let (Some(port), None) = (other.port, &other.host) {
+            match other.connect.take().or(self.connect.take()) {
+                Some(Endpoint::Tcp { host, .. }) => self.host = Some(host),
+                Some(endpoint) => {
+                    return Err(Error::InvalidConfig(
+                        format!("port {} given for {}, which is not a TCP endpoint", port, endpoint)
+                    ));
+                }
+                None => {}
+            }
+        }
+
+        self.connect = other.connect.or(self.connect.take());
+        self.host = other.host.or(self.host.take());
+        self.port = other.port.or(self.port);
+        self.source = other.source.or(self.source.take());
+        self.timeout = other.timeout.or(self.timeout);
+        self.tx_settle = other.tx_settle.or(self.tx_settle);
+        self.tx_retries = other.tx_retries.or(self.tx_retries);
+        self.addresses.extend(other.addresses);
+        Ok(())
+    }
+
+    /// Where to connect to, `None` if neither `connect` nor `host` is set
+    pub fn endpoint(&self) -> Option<Endpoint> {
+        match (&self.connect, &self.host) {
+            (Some(endpoint), _) => Some(endpoint.clone()),
+            (None, Some(host)) => Some(Endpoint::Tcp {
+                host: host.clone(),
+                port: self.port.unwrap_or(DEFAULT_PORT)
+            }),
+            (None, None) => None
+        }
+    }
+
+    /// The configured source address resolved through the address book, `00:23` if there is none
+    ///
+    /// # Errors
+    /// [Error::InvalidConfig] if the source is neither an address nor a known name
+    pub fn source(&self) -> Result<CanAddr> {
+        match &self.source {
+            Some(source) => self.addresses.resolve(source)
+                .map_err(|_| Error::InvalidConfig(format!("source: unknown address {:?}", source))),
+            None => Ok(CanAddr::new(0x00, 0x23).unwrap())
+        }
+    }
+
+    /// Applies timeout and TX settings to `client`
+    pub fn configure(&self, client: &mut CandClient) -> Result<()> {
+        if let Some(timeout) = self.timeout {
+            client.set_timeout(secs("timeout", timeout)?);
+        }
+        if self.tx_settle.is_some() || self.tx_retries.is_some() {
+            let settle = match self.tx_settle {
+                Some(settle) => secs("tx_settle", settle)?,
+                None => DEFAULT_TX_SETTLE
+            };
+            client.set_tx_settle(settle, self.tx_retries.unwrap_or(3));
+        }
+        Ok(())
+    }
+}
+
+/// The user's config file, `$XDG_CONFIG_HOME/labctl/config.toml` or
+/// `~/.config/labctl/config.toml`
+pub fn user_config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("labctl").join("config.toml"))
+}
+
+fn secs(name: &str, secs: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| Error::InvalidConfig(format!("{}: invalid duration {}", name, secs)))
+}
+
+fn parse_var<T: FromStr>(name: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| Error::InvalidConfig(format!("{}: invalid value {:?}", name, value)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::can::CanAddr;
+    use crate::config::Config;
+    use crate::transport::Endpoint;
+
+    #[test]
+    fn test_layers() {
+        let mut config = Config::from_toml(r#"
+            connect = "serial:///dev/ttyUSB0"
+            source = "00:23"
+            timeout = 0.5
+
+            [addresses]
+            borg-main = "24:23"
+        "#).unwrap();
+        assert_eq!(config.endpoint(), Some(Endpoint::Serial { path: "/dev/ttyUSB0".into(), baud: 115_200 }));
+
+        let mut user = Config::from_toml("tx_retries = 5\n[addresses]\nlamps = \"02:02\"").unwrap();
+        user.source = Some("0t1:23".to_string());
+        config.merge(user).unwrap();
+
+        let env = vec![
+            ("LABCTL_HOST".to_string(), "10.0.1.4".to_string()),
+            ("LABCTL_PORT".to_string(), "1234".to_string()),
+            ("LABCTL_TIMEOUT".to_string(), "3".to_string()),
+            ("PATH".to_string(), "/bin".to_string())
+        ];
+        config.merge(Config::from_env_vars(env).unwrap()).unwrap();
+
+        assert_eq!(config.endpoint(), Some(Endpoint::Tcp { host: "10.0.1.4".to_string(), port: 1234 }));
+        assert_eq!(config.source().unwrap(), CanAddr::new(0x01, 0x23).unwrap());
+
+        // Names resolve through the merged address book
+        config.merge(Config::from_env_vars(vec![("LABCTL_SOURCE".to_string(), "lamps:0t3".to_string())]).unwrap()).unwrap();
+        assert_eq!(config.source().unwrap(), CanAddr::new(0x02, 0x03).unwrap());
+        config.source = Some("nowhere".to_string());
+        assert!(config.source().is_err());
+        assert_eq!(Config::default().source().unwrap(), CanAddr::new(0x00, 0x23).unwrap());
+        assert_eq!(config.timeout, Some(3.0));
+        assert_eq!(config.tx_retries, Some(5));
+        assert!(config.addresses.get("borg-main").is_some());
+        assert!(config.addresses.get("lamps").is_some());
+
+        assert!(Config::from_toml("hots = \"10.0.1.4\"").is_err());
+        assert!(Config::from_env_vars(vec![("LABCTL_PORT".to_string(), "cand".to_string())]).is_err());
+        assert_eq!(Config::default().endpoint(), None);
+    }
+
+    #[test]
+    fn test_port_over_endpoint() {
+        let port = || Config::from_env_vars(vec![("LABCTL_PORT".to_string(), "1234".to_string())]).unwrap();
+
+        let mut config = Config::from_toml("connect = \"tcp://10.0.1.4\"").unwrap();
+        config.merge(port()).unwrap();
+        assert_eq!(config.endpoint(), Some(Endpoint::Tcp { host: "10.0.1.4".to_string(), port: 1234 }));
+
+        let mut config = Config::from_toml("connect = \"unix:///run/cand.sock\"").unwrap();
+        assert!(config.merge(port()).is_err());
+
+        let mut config = Config::default();
+        let mut cli = port();
+        cli.connect = Some("tcp://10.0.1.4".parse().unwrap());
+        config.merge(cli).unwrap();
+        assert_eq!(config.endpoint(), Some(Endpoint::Tcp { host: "10.0.1.4".to_string(), port: 1234 }));
+
+        let mut cli = port();
+        cli.connect = Some("unix:///run/cand.sock".parse().unwrap());
+        assert!(Config::default().merge(cli).is_err());
+    }
+}
@@ -1,6 +1,9 @@
+#![allow(non_local_definitions)] // failure_derive predates this lint
+
 use std::io;
 use failure::Fail;
 use std::result::Result as StdResult;
+use crate::can::CanPacket;
 
 pub type Result<T> = StdResult<T, Error>;
 
@@ -16,6 +19,30 @@ pub struct InvalidCanId;
 #[fail(display = "CAN Port out of range")]
 pub struct InvalidCanPort;
 
+#[derive(Fail, Debug)]
+#[fail(display = "Unknown gateway mode")]
+pub struct InvalidGatewayMode;
+
+#[derive(Fail, Debug)]
+#[fail(display = "Unknown controller register")]
+pub struct UnknownRegister;
+
+#[derive(Fail, Debug)]
+#[fail(display = "Not a known LAP message")]
+pub struct UnknownLapMessage;
+
+#[derive(Fail, Debug)]
+#[fail(display = "Expected candump line like (1600000000.000000) can0 1A2B3C4D#0102")]
+pub struct InvalidCandumpLine;
+
+#[derive(Fail, Debug)]
+#[fail(display = "Invalid endpoint, expected tcp://host:port, unix://path or serial://path")]
+pub struct InvalidEndpoint;
+
+#[derive(Fail, Debug)]
+#[fail(display = "Invalid filter, expected an address pattern like 24:* or a payload pattern like 01??")]
+pub struct InvalidFilter;
+
 #[derive(Fail, Debug)]
 pub enum Error {
     #[fail(display = "CAN Port out of range")]
@@ -27,6 +54,36 @@ pub enum Error {
     #[fail(display = "Inner and outer length mismatch")]
     WrongLength,
 
+    #[fail(display = "Malformed message of type 0x{:02x}: length {}, expected {:?}", kind, len, expected)]
+    MalformedMessage { kind: u8, len: usize, expected: &'static [usize] },
+
+    #[fail(display = "Unknown gateway mode 0x{:02x}", _0)]
+    InvalidGatewayMode(u8),
+
+    #[fail(display = "Payload of {} bytes exceeds the maximum of {} bytes", len, max)]
+    PayloadTooLarge { len: usize, max: usize },
+
+    #[fail(display = "Buffer of {} bytes too small, {} bytes needed", len, needed)]
+    BufferTooSmall { len: usize, needed: usize },
+
+    #[fail(display = "Timed out waiting for the gateway")]
+    Timeout,
+
+    #[fail(display = "Connection to cand closed")]
+    Disconnected,
+
+    #[fail(display = "Unsupported baud rate {}", _0)]
+    UnsupportedBaudRate(u32),
+
+    #[fail(display = "Invalid configuration: {}", _0)]
+    InvalidConfig(String),
+
+    #[fail(display = "Not a labctl capture or capture corrupted")]
+    InvalidCapture,
+
+    #[fail(display = "Gateway failed to transmit frame")]
+    TxFailed(CanPacket),
+
     #[fail(display = "IO Error: {}", _0)]
     IOError(#[cause] io::Error)
 }
@@ -0,0 +1,225 @@
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use crate::can::{can_id_from_tuple, can_id_to_tuple, CanPacket, MAX_PAYLOAD};
+use crate::error::{self, Result};
+
+/// Flag marking a SocketCAN ID as 29 bit extended ID
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+
+/// LINKTYPE_CAN_SOCKETCAN
+const LINKTYPE_CAN_SOCKETCAN: u16 = 227;
+
+/// Size of a classic SocketCAN frame: ID, length, three bytes of padding and the payload
+const SOCKETCAN_FRAME_LEN: usize = 8 + MAX_PAYLOAD;
+
+/// Writes CAN frames in a format other tools understand
+pub trait FrameWriter {
+    /// Writes `frame` seen on the bus at `time`
+    fn write_frame(&mut self, time: SystemTime, frame: &CanPacket) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()>;
+}
+
+/// Writes log files in the format of `candump -l`, as read by `canplayer` and `log2asc`
+pub struct CandumpWriter<W: Write> {
+    write: W,
+    interface: String
+}
+
+impl<W: Write> CandumpWriter<W> {
+    /// Logs frames as seen on the SocketCAN interface `interface`, e.g. can0
+    pub fn new(write: W, interface: &str) -> CandumpWriter<W> {
+        CandumpWriter {
+            write,
+            interface: interface.to_string()
+        }
+    }
+}
+
+impl<W: Write> FrameWriter for CandumpWriter<W> {
+    fn write_frame(&mut self, time: SystemTime, frame: &CanPacket) -> Result<()> {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        writeln!(
+            self.write,
+            "({}.{:06}) {} {:08X}#{}",
+            time.as_secs(),
+            time.subsec_micros(),
+            self.interface,
+            can_id_from_tuple(frame.src, frame.dest),
+            hex::encode_upper(&frame.payload)
+        )?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write.flush()?;
+        Ok(())
+    }
+}
+
+/// A line of a `candump -l` log with a 29 bit extended ID, as written by [CandumpWriter]
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct CandumpLine {
+    pub time: SystemTime,
+    pub interface: String,
+    pub frame: CanPacket
+}
+
+impl FromStr for CandumpLine {
+    type Err = error::InvalidCandumpLine;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let (time, interface, frame) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(time), Some(interface), Some(frame), None) => (time, interface, frame),
+            _ => return Err(error::InvalidCandumpLine)
+        };
+
+        let time = time.strip_prefix('(')
+            .and_then(|time| time.strip_suffix(')'))
+            .and_then(|time| time.split_once('.'))
+            .ok_or(error::InvalidCandumpLine)?;
+        if time.1.len() != 6 {
+            return Err(error::InvalidCandumpLine);
+        }
+        let secs = time.0.parse().map_err(|_| error::InvalidCandumpLine)?;
+        let micros = time.1.parse().map_err(|_| error::InvalidCandumpLine)?;
+
+        // Standard IDs have 3 digits and cannot be mapped to LAP addresses, remote and CAN FD
+        // frames are not supported by the gateway
+        let (id, payload) = frame.split_once('#').ok_or(error::InvalidCandumpLine)?;
+        if id.len() != 8 {
+            return Err(error::InvalidCandumpLine);
+        }
+        let id = u32::from_str_radix(id, 16).map_err(|_| error::InvalidCandumpLine)?;
+        let (src, dest) = can_id_to_tuple(id).map_err(|_| error::InvalidCandumpLine)?;
+        let payload = hex::decode(payload).map_err(|_| error::InvalidCandumpLine)?;
+
+        Ok(CandumpLine {
+            time: UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_micros(micros),
+            interface: interface.to_string(),
+            frame: CanPacket::new(src, dest, payload).map_err(|_| error::InvalidCandumpLine)?
+        })
+    }
+}
+
+/// Writes pcapng files with a single SocketCAN interface, which Wireshark dissects as CAN frames
+pub struct PcapngWriter<W: Write> {
+    write: W
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Writes the section header and the interface description
+    pub fn new(mut write: W) -> Result<PcapngWriter<W>> {
+        // Section header block, length of the section unknown
+        write.write_u32::<LittleEndian>(0x0a0d_0d0a)?;
+        write.write_u32::<LittleEndian>(28)?;
+        write.write_u32::<LittleEndian>(0x1a2b_3c4d)?;
+        write.write_u16::<LittleEndian>(1)?;
+        write.write_u16::<LittleEndian>(0)?;
+        write.write_i64::<LittleEndian>(-1)?;
+        write.write_u32::<LittleEndian>(28)?;
+
+        // Interface description block, timestamps default to microseconds
+        write.write_u32::<LittleEndian>(1)?;
+        write.write_u32::<LittleEndian>(20)?;
+        write.write_u16::<LittleEndian>(LINKTYPE_CAN_SOCKETCAN)?;
+        write.write_u16::<LittleEndian>(0)?;
+        write.write_u32::<LittleEndian>(SOCKETCAN_FRAME_LEN as u32)?;
+        write.write_u32::<LittleEndian>(20)?;
+
+        Ok(PcapngWriter { write })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.write
+    }
+}
+
+impl<W: Write> FrameWriter for PcapngWriter<W> {
+    fn write_frame(&mut self, time: SystemTime, frame: &CanPacket) -> Result<()> {
+        let micros = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+        // The SocketCAN header is in network byte order, unlike the pcapng blocks around it
+        let mut data = Vec::with_capacity(SOCKETCAN_FRAME_LEN);
+        data.write_u32::<BigEndian>(can_id_from_tuple(frame.src, frame.dest) | CAN_EFF_FLAG)?;
+        data.write_u8(frame.payload.len() as u8)?;
+        data.extend_from_slice(&[0; 3]);
+        data.extend_from_slice(&frame.payload);
+        data.resize(SOCKETCAN_FRAME_LEN, 0);
+
+        // Enhanced packet block
+        let block_len = 32 + data.len() as u32;
+        self.write.write_u32::<LittleEndian>(6)?;
+        self.write.write_u32::<LittleEndian>(block_len)?;
+        self.write.write_u32::<LittleEndian>(0)?;
+        self.write.write_u32::<LittleEndian>((micros >> 32) as u32)?;
+        self.write.write_u32::<LittleEndian>(micros as u32)?;
+        self.write.write_u32::<LittleEndian>(data.len() as u32)?;
+        self.write.write_u32::<LittleEndian>(data.len() as u32)?;
+        self.write.write_all(&data)?;
+        self.write.write_u32::<LittleEndian>(block_len)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::can::{CanAddr, CanPacket};
+    use crate::export::{CandumpLine, CandumpWriter, FrameWriter, PcapngWriter};
+
+    fn frame() -> CanPacket {
+        CanPacket::new(CanAddr::new(0x00, 0x23).unwrap(), CanAddr::new(0x24, 0x02).unwrap(), vec![0x01, 0x02]).unwrap()
+    }
+
+    #[test]
+    fn test_candump() {
+        let mut buf = Vec::new();
+        let mut writer = CandumpWriter::new(&mut buf, "can0");
+        writer.write_frame(UNIX_EPOCH + Duration::from_micros(1_600_000_000_000_042), &frame()).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "(1600000000.000042) can0 11820024#0102\n");
+    }
+
+    #[test]
+    fn test_parse_candump() {
+        let line: CandumpLine = "(1600000000.000042) can0 11820024#0102".parse().unwrap();
+        assert_eq!(line.time, UNIX_EPOCH + Duration::from_micros(1_600_000_000_000_042));
+        assert_eq!(line.interface, "can0");
+        assert_eq!(line.frame, frame());
+
+        let empty: CandumpLine = "(1600000000.500000) vcan1 1A2B3C4D#".parse().unwrap();
+        assert!(empty.frame.payload.is_empty());
+
+        assert!("(1600000000.000042) can0 123#0102".parse::<CandumpLine>().is_err());
+        assert!("(1600000000.000042) can0 11820024#R".parse::<CandumpLine>().is_err());
+        assert!("(1600000000.000042) can0 11820024##10102".parse::<CandumpLine>().is_err());
+        assert!("(1600000000.000042) can0 11820024#010203040506070809".parse::<CandumpLine>().is_err());
+        assert!("can0 11820024#0102".parse::<CandumpLine>().is_err());
+    }
+
+    #[test]
+    fn test_pcapng() {
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        writer.write_frame(UNIX_EPOCH + Duration::from_micros(0x1_0000_0002), &frame()).unwrap();
+        let buf = writer.into_inner();
+
+        // Section header and interface description
+        assert_eq!(buf.len(), 28 + 20 + 48);
+        assert_eq!(&buf[28 + 8..28 + 10], &[227, 0]);
+
+        let block = &buf[48..];
+        assert_eq!(&block[..8], &[6, 0, 0, 0, 48, 0, 0, 0]);
+        assert_eq!(&block[12..20], &[1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(&block[28..36], &[0x91, 0x82, 0x00, 0x24, 2, 0, 0, 0]);
+        assert_eq!(&block[36..44], &[1, 2, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&block[44..], &[48, 0, 0, 0]);
+    }
+}
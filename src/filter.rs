@@ -0,0 +1,238 @@
+use std::fmt;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+use crate::address_book::AddressBook;
+use crate::can::{parse_addr_part, CanAddr, CanPacket};
+use crate::cand::Message;
+use crate::error;
+
+/// Parses a port like a half of an address, hex unless prefixed with `0t`
+pub fn parse_port(s: &str) -> StdResult<u8, error::InvalidFilter> {
+    match parse_addr_part(s) {
+        Ok(port) if port <= 0x3f => Ok(port),
+        _ => Err(error::InvalidFilter)
+    }
+}
+
+/// Matches addresses with `*` as wildcard for either half, e.g. `24:*` or `*:23`
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct AddrPattern {
+    pub addr: Option<u8>,
+    pub port: Option<u8>
+}
+
+impl AddrPattern {
+    pub fn matches(&self, addr: CanAddr) -> bool {
+        self.addr.unwrap_or(addr.addr()) == addr.addr() && self.port.unwrap_or(addr.port()) == addr.port()
+    }
+
+    /// Parses a pattern, a name from `book` or a name followed by `:*` for all its node's ports
+    pub fn resolve(s: &str, book: &AddressBook) -> StdResult<AddrPattern, error::InvalidFilter> {
+        if let Ok(addr) = book.resolve(s) {
+            return Ok(addr.into());
+        }
+        if let Some(addr) = s.strip_suffix(":*").and_then(|name| book.get(name)) {
+            return Ok(AddrPattern { addr: Some(addr.addr()), port: None });
+        }
+        s.parse()
+    }
+}
+
+impl From<CanAddr> for AddrPattern {
+    fn from(addr: CanAddr) -> AddrPattern {
+        AddrPattern {
+            addr: Some(addr.addr()),
+            port: Some(addr.port())
+        }
+    }
+}
+
+impl FromStr for AddrPattern {
+    type Err = error::InvalidFilter;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        let wildcard = |s: &str, parse: fn(&str) -> StdResult<u8, error::InvalidFilter>| match s {
+            "*" => Ok(None),
+            s => parse(s).map(Some)
+        };
+        match s.split_once(':') {
+            Some((addr, port)) => Ok(AddrPattern {
+                addr: wildcard(addr, |s| parse_addr_part(s).map_err(|_| error::InvalidFilter))?,
+                port: wildcard(port, parse_port)?
+            }),
+            None if s == "*" => Ok(AddrPattern { addr: None, port: None }),
+            None => Err(error::InvalidFilter)
+        }
+    }
+}
+
+impl fmt::Display for AddrPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.addr {
+            Some(addr) => write!(f, "{:02x}:", addr)?,
+            None => write!(f, "*:")?
+        }
+        match self.port {
+            Some(port) => write!(f, "{:02x}", port),
+            None => write!(f, "*")
+        }
+    }
+}
+
+/// Matches payloads starting with the given hex bytes, `?` matches any nibble, e.g. `01??`
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct PayloadPattern {
+    /// Value and mask of every byte
+    bytes: Vec<(u8, u8)>
+}
+
+impl PayloadPattern {
+    pub fn matches(&self, payload: &[u8]) -> bool {
+        payload.len() >= self.bytes.len() && self.bytes.iter()
+            .zip(payload)
+            .all(|((value, mask), byte)| byte & mask == *value)
+    }
+}
+
+impl FromStr for PayloadPattern {
+    type Err = error::InvalidFilter;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        let pairs = s.strip_prefix("0x").unwrap_or(s).as_bytes().chunks_exact(2);
+        if !pairs.remainder().is_empty() {
+            return Err(error::InvalidFilter);
+        }
+
+        let nibble = |c: u8| match c {
+            b'?' => Ok((0, 0)),
+            c => (c as char).to_digit(16)
+                .map(|value| (value as u8, 0xf))
+                .ok_or(error::InvalidFilter)
+        };
+        let bytes = pairs
+            .map(|pair| {
+                let (high, high_mask) = nibble(pair[0])?;
+                let (low, low_mask) = nibble(pair[1])?;
+                Ok((high << 4 | low, high_mask << 4 | low_mask))
+            })
+            .collect::<StdResult<_, _>>()?;
+        Ok(PayloadPattern { bytes })
+    }
+}
+
+/// Selects frames by source, destination, port and payload.
+///
+/// An empty [FrameFilter::All] matches every frame, an empty [FrameFilter::Any] none.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum FrameFilter {
+    Src(AddrPattern),
+    Dest(AddrPattern),
+    /// Matches if either the source or the destination port is the given one
+    Port(u8),
+    Payload(PayloadPattern),
+    All(Vec<FrameFilter>),
+    Any(Vec<FrameFilter>)
+}
+
+impl FrameFilter {
+    pub fn matches(&self, frame: &CanPacket) -> bool {
+        match self {
+            FrameFilter::Src(pattern) => pattern.matches(frame.src),
+            FrameFilter::Dest(pattern) => pattern.matches(frame.dest),
+            FrameFilter::Port(port) => frame.src.port() == *port || frame.dest.port() == *port,
+            FrameFilter::Payload(pattern) => pattern.matches(&frame.payload),
+            FrameFilter::All(filters) => filters.iter().all(|filter| filter.matches(frame)),
+            FrameFilter::Any(filters) => filters.iter().any(|filter| filter.matches(frame))
+        }
+    }
+
+    /// Matches frames and failed transmissions, all other messages pass
+    pub fn matches_message(&self, message: &Message) -> bool {
+        match message {
+            Message::Frame(frame) | Message::TxFailed(frame) => self.matches(frame),
+            _ => true
+        }
+    }
+
+    pub fn and(self, other: FrameFilter) -> FrameFilter {
+        match self {
+            FrameFilter::All(mut filters) => {
+                filters.push(other);
+                FrameFilter::All(filters)
+            }
+            filter => FrameFilter::All(vec![filter, other])
+        }
+    }
+
+    pub fn or(self, other: FrameFilter) -> FrameFilter {
+        match self {
+            FrameFilter::Any(mut filters) => {
+                filters.push(other);
+                FrameFilter::Any(filters)
+            }
+            filter => FrameFilter::Any(vec![filter, other])
+        }
+    }
+}
+
+impl Default for FrameFilter {
+    fn default() -> FrameFilter {
+        FrameFilter::All(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::address_book::AddressBook;
+    use crate::can::{CanAddr, CanPacket};
+    use crate::filter::{parse_port, AddrPattern, FrameFilter, PayloadPattern};
+
+    fn frame(src: (u8, u8), dest: (u8, u8), payload: &[u8]) -> CanPacket {
+        CanPacket::new(
+            CanAddr::new(src.0, src.1).unwrap(),
+            CanAddr::new(dest.0, dest.1).unwrap(),
+            payload.to_vec()
+        ).unwrap()
+    }
+
+    #[test]
+    fn test_patterns() {
+        let src: AddrPattern = "24:*".parse().unwrap();
+        assert!(src.matches(CanAddr::new(0x24, 0x02).unwrap()));
+        assert!(!src.matches(CanAddr::new(0x25, 0x02).unwrap()));
+        assert_eq!(src.to_string(), "24:*");
+        assert_eq!("*:0t35".parse::<AddrPattern>().unwrap(), AddrPattern { addr: None, port: Some(0x23) });
+        assert!("*:40".parse::<AddrPattern>().is_err());
+        assert!("24".parse::<AddrPattern>().is_err());
+        assert_eq!(parse_port("0x02").unwrap(), 0x02);
+        assert!(parse_port("40").is_err());
+
+        let mut book = AddressBook::new();
+        book.insert("borg-main", CanAddr::new(0x24, 0x23).unwrap());
+        assert_eq!(AddrPattern::resolve("borg-main", &book).unwrap(), AddrPattern { addr: Some(0x24), port: Some(0x23) });
+        assert_eq!(AddrPattern::resolve("borg-main:*", &book).unwrap(), AddrPattern { addr: Some(0x24), port: None });
+
+        let payload: PayloadPattern = "01?f".parse().unwrap();
+        assert!(payload.matches(&[0x01, 0xaf, 0x00]));
+        assert!(!payload.matches(&[0x01, 0xa0]));
+        assert!(!payload.matches(&[0x01]));
+        assert!("01?".parse::<PayloadPattern>().is_err());
+        assert!("0g".parse::<PayloadPattern>().is_err());
+    }
+
+    #[test]
+    fn test_combine() {
+        let borg = FrameFilter::Dest("*:23".parse().unwrap())
+            .and(FrameFilter::Payload("03".parse().unwrap()));
+        let lamps = FrameFilter::Port(0x02);
+        let filter = borg.or(lamps);
+
+        assert!(filter.matches(&frame((0x00, 0x23), (0x24, 0x23), &[0x03, b'H'])));
+        assert!(!filter.matches(&frame((0x00, 0x23), (0x24, 0x23), &[0x01, 0x01])));
+        assert!(filter.matches(&frame((0x3c, 0x02), (0x00, 0x23), &[0x00, 6, 255])));
+        assert!(!filter.matches(&frame((0x3c, 0x01), (0x00, 0x01), &[0x03])));
+
+        assert!(FrameFilter::default().matches(&frame((0, 0), (0, 0), &[])));
+        assert!(!FrameFilter::Any(Vec::new()).matches(&frame((0, 0), (0, 0), &[])));
+    }
+}
@@ -3,16 +3,43 @@ pub trait LapPacket {
 }
 
 use crate::can::{CanPacket, CanAddr};
-use std::cmp;
+use crate::error;
+use std::{cmp, fmt};
+use std::convert::TryFrom;
+
+/// Port of the lamp controllers
+pub const PORT_LAMP: u8 = 0x02;
+
+/// Port of the borgs
+pub const PORT_BORG: u8 = 0x23;
 
 //#[derive(FromPrimitive)]
-#[derive(Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum LampMode {
     Toggle = 0,
     Dim = 1
 }
 
-#[derive(Clone, Copy)]
+impl LampMode {
+    pub fn from_u8(mode: u8) -> Option<LampMode> {
+        match mode {
+            0 => Some(LampMode::Toggle),
+            1 => Some(LampMode::Dim),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for LampMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LampMode::Toggle => write!(f, "toggle"),
+            LampMode::Dim => write!(f, "dim")
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum BorgMessage {
     Info = 0,
     Mode = 1,
@@ -20,12 +47,44 @@ pub enum BorgMessage {
     ScrollAppend = 3
 }
 
+impl BorgMessage {
+    pub fn from_u8(kind: u8) -> Option<BorgMessage> {
+        match kind {
+            0 => Some(BorgMessage::Info),
+            1 => Some(BorgMessage::Mode),
+            2 => Some(BorgMessage::ScrollReset),
+            3 => Some(BorgMessage::ScrollAppend),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct SetLampPacket {
     pub mode: LampMode,
     pub lamp_id: u8,
     pub value: u8
 }
 
+impl SetLampPacket {
+    pub fn from_payload(payload: &[u8]) -> Option<SetLampPacket> {
+        match payload {
+            [mode, lamp_id, value] => Some(SetLampPacket {
+                mode: LampMode::from_u8(*mode)?,
+                lamp_id: *lamp_id,
+                value: *value
+            }),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for SetLampPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SetLamp {} lamp={} value={}", self.mode, self.lamp_id, self.value)
+    }
+}
+
 impl LapPacket for SetLampPacket {
     fn to_can(&self, src: CanAddr, dest: CanAddr) -> CanPacket {
         CanPacket {
@@ -36,8 +95,15 @@ impl LapPacket for SetLampPacket {
     }
 }
 
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct ClearBorgText;
 
+impl fmt::Display for ClearBorgText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Borg ScrollReset")
+    }
+}
+
 impl LapPacket for ClearBorgText {
     fn to_can(&self, src: CanAddr, dest: CanAddr) -> CanPacket {
         CanPacket {
@@ -48,8 +114,15 @@ impl LapPacket for ClearBorgText {
     }
 }
 
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct BorgMode(pub u8);
 
+impl fmt::Display for BorgMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Borg Mode {}", self.0)
+    }
+}
+
 impl LapPacket for BorgMode {
     fn to_can(&self, src: CanAddr, dest: CanAddr) -> CanPacket {
         CanPacket {
@@ -60,15 +133,40 @@ impl LapPacket for BorgMode {
     }
 }
 
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub struct AppendBorgText {
     text: [u8; 7]
 }
 
+impl AppendBorgText {
+    /// Returns `None` if `text` is longer than the 7 bytes fitting into one packet
+    pub fn new(text: &[u8]) -> Option<AppendBorgText> {
+        if text.len() > 7 {
+            return None;
+        }
+        let mut append = AppendBorgText { text: [0; 7] };
+        copy_data(text, &mut append.text);
+        Some(append)
+    }
+
+    /// The text without trailing padding
+    pub fn text(&self) -> &[u8] {
+        let len = self.text.iter().rposition(|c| *c != 0).map_or(0, |idx| idx + 1);
+        &self.text[..len]
+    }
+}
+
+impl fmt::Display for AppendBorgText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Borg ScrollAppend {:?}", String::from_utf8_lossy(self.text()))
+    }
+}
+
 impl LapPacket for AppendBorgText {
     fn to_can(&self, src: CanAddr, dest: CanAddr) -> CanPacket {
         let payload = [0x03].iter()
             .chain(self.text.iter())
-            .map(|i| *i)
+            .copied()
             .collect();
         CanPacket {
             src,
@@ -78,6 +176,60 @@ impl LapPacket for AppendBorgText {
     }
 }
 
+/// A received LAP packet of one of the known types
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum LapMessage {
+    SetLamp(SetLampPacket),
+    BorgMode(BorgMode),
+    ClearBorgText(ClearBorgText),
+    AppendBorgText(AppendBorgText)
+}
+
+/// Dispatches on the destination port and the first payload byte
+impl TryFrom<&CanPacket> for LapMessage {
+    type Error = error::UnknownLapMessage;
+
+    fn try_from(packet: &CanPacket) -> Result<Self, Self::Error> {
+        let payload = packet.payload.as_slice();
+        let message = match packet.dest.port() {
+            PORT_LAMP => SetLampPacket::from_payload(payload).map(LapMessage::SetLamp),
+            PORT_BORG => match payload.split_first() {
+                Some((kind, data)) => match (BorgMessage::from_u8(*kind), data) {
+                    (Some(BorgMessage::Mode), [mode]) => Some(LapMessage::BorgMode(BorgMode(*mode))),
+                    (Some(BorgMessage::ScrollReset), []) => Some(LapMessage::ClearBorgText(ClearBorgText)),
+                    (Some(BorgMessage::ScrollAppend), text) => AppendBorgText::new(text).map(LapMessage::AppendBorgText),
+                    _ => None
+                },
+                None => None
+            },
+            _ => None
+        };
+        message.ok_or(error::UnknownLapMessage)
+    }
+}
+
+impl LapPacket for LapMessage {
+    fn to_can(&self, src: CanAddr, dest: CanAddr) -> CanPacket {
+        match self {
+            LapMessage::SetLamp(p) => p.to_can(src, dest),
+            LapMessage::BorgMode(p) => p.to_can(src, dest),
+            LapMessage::ClearBorgText(p) => p.to_can(src, dest),
+            LapMessage::AppendBorgText(p) => p.to_can(src, dest)
+        }
+    }
+}
+
+impl fmt::Display for LapMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LapMessage::SetLamp(p) => p.fmt(f),
+            LapMessage::BorgMode(p) => p.fmt(f),
+            LapMessage::ClearBorgText(p) => p.fmt(f),
+            LapMessage::AppendBorgText(p) => p.fmt(f)
+        }
+    }
+}
+
 pub fn set_scroll_text(input: &str, src: CanAddr, dst: CanAddr) -> Vec<CanPacket> {
     let input_data = input.as_bytes();
     let mut buf = Vec::with_capacity(2 + input.len() / 7);
@@ -87,7 +239,7 @@ pub fn set_scroll_text(input: &str, src: CanAddr, dst: CanAddr) -> Vec<CanPacket
         let substr = &input_data[idx..];
         let sub = &substr[..cmp::min(substr.len(), 7)];
         let mut text = [0; 7];
-        copy_data(&sub, &mut text);
+        copy_data(sub, &mut text);
         buf.push(AppendBorgText { text }.to_can(src, dst));
         idx += 7;
     }
@@ -98,4 +250,54 @@ fn copy_data(src: &[u8], dest: &mut [u8]) {
     for (idx, el) in src.iter().enumerate() {
         dest[idx] = *el;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+    use crate::can::{CanAddr, CanPacket};
+    use crate::lap::{set_scroll_text, AppendBorgText, BorgMode, ClearBorgText, LampMode, LapMessage, LapPacket, SetLampPacket, PORT_BORG, PORT_LAMP};
+
+    fn recode(message: LapMessage, port: u8) {
+        let packet = message.to_can(CanAddr::new(0x00, 0x23).unwrap(), CanAddr::new(0x24, port).unwrap());
+        assert_eq!(LapMessage::try_from(&packet).unwrap(), message);
+    }
+
+    #[test]
+    fn test_recode() {
+        recode(LapMessage::SetLamp(SetLampPacket { mode: LampMode::Toggle, lamp_id: 6, value: 255 }), PORT_LAMP);
+        recode(LapMessage::SetLamp(SetLampPacket { mode: LampMode::Dim, lamp_id: 2, value: 0x80 }), PORT_LAMP);
+        recode(LapMessage::BorgMode(BorgMode(1)), PORT_BORG);
+        recode(LapMessage::ClearBorgText(ClearBorgText), PORT_BORG);
+        recode(LapMessage::AppendBorgText(AppendBorgText::new(b"Hello W").unwrap()), PORT_BORG);
+        recode(LapMessage::AppendBorgText(AppendBorgText::new(b"orld").unwrap()), PORT_BORG);
+    }
+
+    #[test]
+    fn test_display() {
+        let src = CanAddr::new(0x00, 0x23).unwrap();
+        let lamp = SetLampPacket { mode: LampMode::Toggle, lamp_id: 6, value: 255 }
+            .to_can(src, CanAddr::new(0x3c, PORT_LAMP).unwrap());
+        assert_eq!(LapMessage::try_from(&lamp).unwrap().to_string(), "SetLamp toggle lamp=6 value=255");
+
+        let borg = set_scroll_text("Hello World", src, CanAddr::new(0x24, PORT_BORG).unwrap());
+        let described: Vec<_> = borg.iter().map(|p| LapMessage::try_from(p).unwrap().to_string()).collect();
+        assert_eq!(described, vec!["Borg ScrollReset", "Borg ScrollAppend \"Hello W\"", "Borg ScrollAppend \"orld\""]);
+    }
+
+    #[test]
+    fn test_unknown() {
+        let src = CanAddr::new(0x00, 0x23).unwrap();
+        let unknown = |port, payload: &[u8]| CanPacket {
+            src,
+            dest: CanAddr::new(0x24, port).unwrap(),
+            payload: payload.to_vec()
+        };
+        assert!(LapMessage::try_from(&unknown(PORT_LAMP, &[0x07, 6, 255])).is_err());
+        assert!(LapMessage::try_from(&unknown(PORT_LAMP, &[0x00, 6])).is_err());
+        assert!(LapMessage::try_from(&unknown(PORT_BORG, &[0x00])).is_err());
+        assert!(LapMessage::try_from(&unknown(PORT_BORG, &[0x02, 0x00])).is_err());
+        assert!(LapMessage::try_from(&unknown(PORT_BORG, &[])).is_err());
+        assert!(LapMessage::try_from(&unknown(0x10, &[0x01, 0x01])).is_err());
+    }
+}
@@ -1,4 +1,16 @@
+pub mod address_book;
 pub mod can;
 pub mod lap;
 pub mod cand;
-pub mod error;
\ No newline at end of file
+pub mod capture;
+pub mod config;
+pub mod error;
+pub mod export;
+pub mod filter;
+pub mod mcp2515;
+pub mod sim;
+#[cfg(unix)]
+pub mod serial;
+#[cfg(unix)]
+pub mod server;
+pub mod transport;
\ No newline at end of file
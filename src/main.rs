@@ -5,57 +5,184 @@ extern crate clap;
 
 extern crate labctl;
 
-use byteorder::ReadBytesExt;
-use std::net::TcpStream;
-use std::io::{Read, Cursor, Write};
+use labctl::address_book::AddressBook;
+use labctl::config::Config;
 use labctl::can::CanAddr;
-use labctl::lap::{BorgMode, LapPacket};
+use labctl::lap::{LapMessage, LapPacket};
+use std::convert::TryFrom;
 use std::thread;
-use std::time::Duration;
-use labctl::cand::Message;
+use std::time::{Duration, Instant};
+use labctl::cand::{CandClient, CanFilter, GatewayMode, Message};
+use labctl::capture::{CaptureReader, CaptureWriter};
+use labctl::export::{CandumpLine, CandumpWriter, FrameWriter, PcapngWriter};
+use labctl::filter::{self, AddrPattern, FrameFilter};
+use labctl::mcp2515::Register;
+use labctl::error::Error;
+#[cfg(unix)]
+use labctl::server::CandServer;
+use labctl::transport::Endpoint;
+#[cfg(unix)]
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn args<'a, 'b>() -> clap::App<'a, 'b> {
-    clap_app!{labctl =>
+    let app = clap_app!{labctl =>
         (version: "0.1")
         (author: "kilobyte22")
         (about: "Controls the Lab")
         (setting: clap::AppSettings::SubcommandRequiredElseHelp)
-        (@arg host: -h +required +takes_value "The host to connect to")
-        (@arg port: -p +takes_value "The port the cand listens on")
+        (@arg connect: -c --connect +takes_value conflicts_with[host]
+            "Where to connect to: tcp://host:port, unix:///path/to/socket or serial:///dev/tty?baud=115200")
+        (@arg host: -h +takes_value "The host to connect to, short for --connect tcp://host")
+        (@arg port: -p +takes_value "The port the cand listens on, also for a tcp:// endpoint from the config")
+        (@arg source: --source +takes_value "The source address of sent frames, hex halves or 0t for decimal, or a name (default 00:23)")
+        (@arg timeout: --timeout +takes_value "Seconds to wait for the answer to a request (default 2)")
+        (@arg tx_settle: --("tx-settle") +takes_value "Seconds to wait for a TX failure after every frame (default 0.03)")
+        (@arg tx_retries: --("tx-retries") +takes_value "How often to resend a failed frame (default 3)")
         (@subcommand monitor =>
-            (@arg decode: -d)
+            (@arg decode: -d --decode "Decode known LAP payloads")
+            (@arg strict: -s --strict "Report known messages with unexpected length as malformed")
+            (@arg src: --src +takes_value +multiple number_of_values(1) "Only frames from matching addresses, e.g. 24:*, *:0t35 or a name")
+            (@arg dest: --dest +takes_value +multiple number_of_values(1) "Only frames to matching addresses, halves are hex unless prefixed with 0t")
+            (@arg port: --port +takes_value +multiple number_of_values(1) "Only frames from or to this port, hex unless prefixed with 0t")
+            (@arg payload: --payload +takes_value +multiple number_of_values(1) "Only frames with a payload starting like this, ? matches any nibble, e.g. 01??")
+            (@arg any: --any "Only frames matching any filter instead of all of them")
+        )
+        (@subcommand record =>
+            (about: "Records all messages from the gateway to a capture file until interrupted")
+            (@arg src: --src +takes_value +multiple number_of_values(1) "Only frames from matching addresses, e.g. 24:*, *:0t35 or a name")
+            (@arg dest: --dest +takes_value +multiple number_of_values(1) "Only frames to matching addresses, halves are hex unless prefixed with 0t")
+            (@arg port: --port +takes_value +multiple number_of_values(1) "Only frames from or to this port, hex unless prefixed with 0t")
+            (@arg payload: --payload +takes_value +multiple number_of_values(1) "Only frames with a payload starting like this, ? matches any nibble, e.g. 01??")
+            (@arg any: --any "Only frames matching any filter instead of all of them")
+            (@arg FILE: +required "The capture file to write")
+        )
+        (@subcommand replay =>
+            (about: "Sends the frames of a capture file with their original timing")
+            (@arg speed: --speed +takes_value {is_speed} "Factor to speed up the replay by, 0.001 to 1000 (default 1)")
+            (@arg src: --src +takes_value +multiple number_of_values(1) "Only frames from matching addresses, e.g. 24:*, *:0t35 or a name")
+            (@arg dest: --dest +takes_value +multiple number_of_values(1) "Only frames to matching addresses, halves are hex unless prefixed with 0t")
+            (@arg port: --port +takes_value +multiple number_of_values(1) "Only frames from or to this port, hex unless prefixed with 0t")
+            (@arg payload: --payload +takes_value +multiple number_of_values(1) "Only frames with a payload starting like this, ? matches any nibble, e.g. 01??")
+            (@arg any: --any "Only frames matching any filter instead of all of them")
+            (@arg FILE: +required "The capture file to read")
+        )
+        (@subcommand send_log =>
+            (name: "send-log")
+            (about: "Sends the frames of a candump log with their original timing")
+            (@arg speed: --speed +takes_value {is_speed} "Factor to speed up sending by, 0.001 to 1000 (default 1)")
+            (@arg FILE: +required "The log as written by candump -l")
+        )
+        (@subcommand export =>
+            (about: "Converts frames from a capture file or live traffic for use with other tools")
+            (@arg format: -f --format +takes_value possible_values(&["candump", "pcapng"]) "The output format (default candump)")
+            (@arg output: -o --output +takes_value "The file to write, stdout if omitted")
+            (@arg interface: -i --interface +takes_value "The interface name for candump logs (default can0)")
+            (@arg CAPTURE: "The capture file to convert, live traffic if omitted")
         )
         (@subcommand borg =>
             (@subcommand text =>
                 (@arg now: --now "Instantly show mode 1")
-                (@arg DEST: +required "The destination address")
+                (@arg DEST: +required "The destination address, e.g. 24:23 or 0t36:0t35, or its name from the address book")
                 (@arg TEXT: +required "The Text to display in Fucky Borg Script")
             )
             (@subcommand mode =>
-                (@arg DEST: +required "The destination address")
+                (@arg DEST: +required "The destination address, e.g. 24:23 or 0t36:0t35, or its name from the address book")
                 (@arg MODE: +required "The mode to set")
             )
             (setting: clap::AppSettings::SubcommandRequiredElseHelp)
         )
         (@subcommand power => )
+        (@subcommand stats =>
+            (about: "Polls the packet and error counters of the gateway")
+            (@arg interval: -i --interval +takes_value {is_secs} "Seconds between two polls (default 1)")
+            (@arg count: -n --count +takes_value "Stop after this many polls")
+        )
+        (@subcommand filter =>
+            (about: "Sets the acceptance filter of the gateway")
+            (@arg all: --all conflicts_with[MASK FILTER] "Accept every frame")
+            (@arg MASK: required_unless[all] "The acceptance mask as hex CAN ID")
+            (@arg FILTER: required_unless[all] +multiple max_values(6) "One to six acceptance filters as hex CAN IDs")
+        )
+        (@subcommand gateway =>
+            (@subcommand mode =>
+                (about: "Sets the operating mode of the gateway's CAN controller")
+                (@arg MODE: +required possible_values(&["normal", "loopback", "listen-only", "config"]) "The mode to set")
+            )
+            (@subcommand reg =>
+                (about: "Reads or writes registers of the gateway's CAN controller")
+                (@arg set: -s --set +takes_value requires[REG] "Write this hex value before reading back")
+                (@arg REG: "Register name or hex address, all known registers if omitted")
+            )
+            (@subcommand reset_cause =>
+                (name: "reset-cause")
+                (about: "Asks the gateway why it was last reset")
+            )
+            (setting: clap::AppSettings::SubcommandRequiredElseHelp)
+        )
+    };
+    #[cfg(unix)]
+    let app = app.subcommand(cand_serve_args());
+    app
+}
+
+/// The server needs the serial transport, which is only available on unix
+#[cfg(unix)]
+fn cand_serve_args<'a, 'b>() -> clap::App<'a, 'b> {
+    clap_app!{cand_serve =>
+        (name: "cand-serve")
+        (about: "Serves a gateway on a serial port to cand clients")
+        (@arg listen: -l --listen +takes_value "The address to listen on (default 0.0.0.0:2342)")
+        (@arg baud: -b --baud +takes_value "The baud rate of the serial port (default 115200)")
+        (@arg TTY: +required "The serial port the gateway is connected to, or any other endpoint URL")
     }
 }
 
-fn monitor<R: Read>(sock: &mut R) -> Result<(), failure::Error> {
-    while let Some(message) = labctl::cand::read_packet(sock)? {
+fn monitor(client: &mut CandClient, strict: bool, decode: bool, filter: &FrameFilter, book: &AddressBook) -> Result<(), failure::Error> {
+    client.set_strict(strict);
+    loop {
+        let message = match client.recv() {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(e @ Error::MalformedMessage { .. }) | Err(e @ Error::InvalidGatewayMode(_)) => {
+                println!("[!] {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into())
+        };
+        if !filter.matches_message(&message) {
+            continue;
+        }
         match message {
+            Message::SetFilter(filter) => {
+                // Will usually not be transmitted to clients
+                println!("[?] Set Filter: {:?}", filter)
+            }
             Message::Frame(can_packet) => {
+                let decoded = if decode {
+                    LapMessage::try_from(&can_packet).ok().map(|message| message.to_string())
+                } else {
+                    None
+                };
                 println!(
                     "    {} -> {} {}",
-                    can_packet.src,
-                    can_packet.dest,
-                    can_packet.payload
+                    book.display(can_packet.src),
+                    book.display(can_packet.dest),
+                    decoded.unwrap_or_else(|| can_packet.payload
                         .iter()
                         .map(|b| format!("{:02x}", b))
                         .collect::<Vec<_>>()
-                        .join(" ")
+                        .join(" "))
                 );
             }
+            Message::SetMode(mode) => {
+                // Will usually not be transmitted to clients
+                println!("[?] Set Gateway Mode: {}", mode)
+            }
             Message::Reset { cause } => {
                 println!("[*] Gateway Reset, cause: {}", cause)
             }
@@ -79,9 +206,39 @@ fn monitor<R: Read>(sock: &mut R) -> Result<(), failure::Error> {
             Message::FirmwareIdResponse(fwid) => {
                 println!("[*] Firmware ID String is {:?}", fwid);
             }
+            Message::ReadCtlReg { reg } => {
+                println!("[?] Read Controller Register 0x{:02x}", reg)
+            }
+            Message::ReadCtlRegReply { reg, value } => {
+                println!("[*] Controller Register 0x{:02x} = 0x{:02x}", reg, value)
+            }
+            Message::WriteCtlReg { reg, value } => {
+                println!("[?] Write Controller Register 0x{:02x} = 0x{:02x}", reg, value)
+            }
+            Message::ResetCauseRequest => {
+                println!("[?] Reset Cause Request")
+            }
+            Message::ResetCauseReply(cause) => {
+                println!("[*] Gateway was last reset by {}", cause)
+            }
+            Message::TxFailed(can_packet) => {
+                println!("[!] Transmission failed: {} -> {} {}", book.display(can_packet.src), book.display(can_packet.dest), hex::encode(&can_packet.payload))
+            }
             Message::Unknown { kind, payload } => {
                 println!("[!] Unknown Packet (Type {}): {}", kind, hex::encode(&payload))
             }
+            Message::PacketCountersRequest => {
+                println!("[?] Packet Counters Request")
+            }
+            Message::PacketCounters { rx_count, tx_count } => {
+                println!("[*] Packet Counters: rx: {}, tx: {}", rx_count, tx_count)
+            }
+            Message::ErrorCountersRequest => {
+                println!("[?] Error Counters Request")
+            }
+            Message::ErrorCounters { rx_errors, tx_errors } => {
+                println!("[*] Error Counters: rx: {}, tx: {}", rx_errors, tx_errors)
+            }
             Message::BusPowerRequest => {
                 println!("[?] Bus Power Request")
             }
@@ -93,94 +250,495 @@ fn monitor<R: Read>(sock: &mut R) -> Result<(), failure::Error> {
     Ok(())
 }
 
-fn borg_text<W: Write>(write: &mut W, text: &str, dst: CanAddr) -> Result<(), failure::Error> {
-    for p in labctl::lap::set_scroll_text(text, CanAddr::new(0, 0x23)?, dst) {
-        labctl::cand::write_packet_to_cand(write, &Message::Frame(p))?;
-        write.flush()?;
-        thread::sleep(Duration::from_millis(30));
+fn record(client: &mut CandClient, path: &str, filter: &FrameFilter) -> Result<(), failure::Error> {
+    let mut writer = CaptureWriter::new(BufWriter::new(File::create(path)?), SystemTime::now())?;
+    let start = Instant::now();
+    loop {
+        let message = match client.recv() {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(e @ Error::MalformedMessage { .. }) | Err(e @ Error::InvalidGatewayMode(_)) => {
+                eprintln!("[!] Not recording undecodable message: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into())
+        };
+        if !filter.matches_message(&message) {
+            continue;
+        }
+        match writer.write(start.elapsed(), &message) {
+            Ok(()) => {}
+            Err(e @ Error::PayloadTooLarge { .. }) => {
+                eprintln!("[!] Not recording {:?}: {}", message, e);
+                continue;
+            }
+            Err(e) => return Err(e.into())
+        }
+        // Recording usually ends with ^C, so nothing may be left in the buffer
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Builds the filter given by `--src`, `--dest`, `--port` and `--payload`.
+///
+/// Repeating an option matches any of its values, different options all have to match unless
+/// `--any` is given.
+fn frame_filter(args: &clap::ArgMatches, book: &AddressBook) -> Result<FrameFilter, failure::Error> {
+    let values = |name| args.values_of(name).into_iter().flatten();
+    let groups = vec![
+        values("src").map(|src| AddrPattern::resolve(src, book).map(FrameFilter::Src)).collect::<Result<Vec<_>, _>>()?,
+        values("dest").map(|dest| AddrPattern::resolve(dest, book).map(FrameFilter::Dest)).collect::<Result<Vec<_>, _>>()?,
+        values("port").map(|port| filter::parse_port(port).map(FrameFilter::Port)).collect::<Result<Vec<_>, _>>()?,
+        values("payload").map(|payload| payload.parse().map(FrameFilter::Payload)).collect::<Result<Vec<_>, _>>()?
+    ];
+
+    let filter = if args.is_present("any") {
+        FrameFilter::Any(groups.into_iter().flatten().collect())
+    } else {
+        FrameFilter::All(groups.into_iter()
+            .filter(|group| !group.is_empty())
+            .map(FrameFilter::Any)
+            .collect())
+    };
+    Ok(filter)
+}
+
+/// Slowest and fastest replay speed, beyond these the delays are meaningless
+const SPEED_RANGE: std::ops::RangeInclusive<f64> = 0.001..=1000.0;
+
+/// Sleeps so that frames are sent with the time between them in a log, divided by `speed`
+struct Pacer {
+    speed: f64,
+    /// When the first frame was sent and its time in the log
+    first: Option<(Instant, Duration)>
+}
+
+impl Pacer {
+    fn new(speed: f64) -> Result<Pacer, failure::Error> {
+        if !SPEED_RANGE.contains(&speed) {
+            return Err(failure::err_msg(format!(
+                "The replay speed has to be between {} and {}", SPEED_RANGE.start(), SPEED_RANGE.end()
+            )));
+        }
+        Ok(Pacer { speed, first: None })
+    }
+
+    /// Waits until the frame logged at `time` is due
+    fn wait(&mut self, time: Duration) -> Result<(), failure::Error> {
+        let (start, offset) = *self.first.get_or_insert((Instant::now(), time));
+        let due = Duration::try_from_secs_f64(time.saturating_sub(offset).as_secs_f64() / self.speed)
+            .ok()
+            .and_then(|delay| start.checked_add(delay))
+            .ok_or_else(|| failure::err_msg(format!("A frame at {:?} is too far after the first one", time)))?;
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        Ok(())
+    }
+}
+
+fn replay(client: &mut CandClient, path: &str, speed: f64, filter: &FrameFilter) -> Result<(), failure::Error> {
+    let mut pacer = Pacer::new(speed)?;
+    let reader = CaptureReader::new(BufReader::new(File::open(path)?))?;
+    for record in reader {
+        let record = record?;
+        let frame = match record.message {
+            Message::Frame(frame) => frame,
+            _ => continue
+        };
+        if !filter.matches(&frame) {
+            continue;
+        }
+
+        pacer.wait(record.time)?;
+        client.send(&Message::Frame(frame))?;
+    }
+    Ok(())
+}
+
+fn send_log(client: &mut CandClient, path: &str, speed: f64) -> Result<(), failure::Error> {
+    let mut pacer = Pacer::new(speed)?;
+    for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line: CandumpLine = line.parse()
+            .map_err(|e| failure::err_msg(format!("{}:{}: {}", path, idx + 1, e)))?;
+
+        pacer.wait(line.time.duration_since(UNIX_EPOCH).unwrap_or_default())?;
+        client.send(&Message::Frame(line.frame))?;
+    }
+    Ok(())
+}
+
+fn frame_writer(args: &clap::ArgMatches) -> Result<Box<dyn FrameWriter>, failure::Error> {
+    let output: Box<dyn Write> = match args.value_of("output") {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout())
+    };
+    match args.value_of("format").unwrap_or("candump") {
+        "pcapng" => Ok(Box::new(PcapngWriter::new(output)?)),
+        _ => Ok(Box::new(CandumpWriter::new(output, args.value_of("interface").unwrap_or("can0"))))
+    }
+}
+
+fn export_capture(args: &clap::ArgMatches) -> Result<(), failure::Error> {
+    let mut writer = frame_writer(args)?;
+    let reader = CaptureReader::new(BufReader::new(File::open(args.value_of("CAPTURE").unwrap())?))?;
+    let start = reader.start();
+    for record in reader {
+        let record = record?;
+        if let Message::Frame(frame) = record.message {
+            writer.write_frame(start + record.time, &frame)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn export_live(client: &mut CandClient, args: &clap::ArgMatches) -> Result<(), failure::Error> {
+    let mut writer = frame_writer(args)?;
+    while let Some(message) = client.recv()? {
+        if let Message::Frame(frame) = message {
+            writer.write_frame(SystemTime::now(), &frame)?;
+            // Keep up with readers of a pipe, e.g. wireshark -k -i -
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn borg_text(client: &mut CandClient, text: &str, src: CanAddr, dst: CanAddr) -> Result<(), failure::Error> {
+    for p in labctl::lap::set_scroll_text(text, src, dst) {
+        client.send_frame(&p)?;
     }
     Ok(())
 }
 
-fn borg_mode<W: Write>(write: &mut W, mode: u8, dst: CanAddr) -> Result<(), failure::Error> {
+fn borg_mode(client: &mut CandClient, mode: u8, src: CanAddr, dst: CanAddr) -> Result<(), failure::Error> {
     let p = labctl::lap::BorgMode(mode)
-        .to_can(CanAddr::new(0, 0x23)?, dst);
-    labctl::cand::write_packet_to_cand(write, &Message::Frame(p))?;
+        .to_can(src, dst);
+    client.send_frame(&p)?;
     Ok(())
 }
 
-fn bus_power<W: Write, R: Read>(write: &mut W, read: &mut R) -> Result<(), failure::Error> {
-    labctl::cand::write_packet_to_cand(write, &Message::BusPowerRequest)?;
-    while let Some(msg) = labctl::cand::read_packet(read)? {
-        match msg {
-            Message::BusPowerResponse { v, i, reference, gnd } => {
-                let uadc = v as f64 * (5f64 / 1023f64);
-                let ubus = (uadc * 3_700f64) / 1_000f64;
-                let iadc = i as f64 * (5f64 / 1023f64);
-                let ibus = iadc / (10f64 * 0.01);
-
-                println!("Bus Power:");
-                println!("  U:   {:.02} V", ubus);
-                println!("  I:   {:.02} A", ibus);
-                println!("    => {:.02} W", ubus * ibus);
-                println!("  ref: {}", reference);
-                println!("  gnd: {}", gnd);
-                break;
-            },
-            _ => {}
+fn bus_power(client: &mut CandClient) -> Result<(), failure::Error> {
+    let power = client.bus_power()?;
+    let ubus = power.voltage();
+    let ibus = power.current();
+
+    println!("Bus Power:");
+    println!("  U:   {:.02} V", ubus);
+    println!("  I:   {:.02} A", ibus);
+    println!("    => {:.02} W", ubus * ibus);
+    println!("  ref: {}", power.reference);
+    println!("  gnd: {}", power.gnd);
+    Ok(())
+}
+
+/// Absolute values of all gateway counters at one point in time
+#[derive(Default)]
+struct Counters {
+    rx_count: u32,
+    tx_count: u32,
+    rx_errors: u32,
+    tx_errors: u32
+}
+
+impl Counters {
+    fn values(&self) -> [(&'static str, u32); 4] {
+        [
+            ("rx", self.rx_count),
+            ("tx", self.tx_count),
+            ("rx errors", self.rx_errors),
+            ("tx errors", self.tx_errors)
+        ]
+    }
+}
+
+fn poll_counters(client: &mut CandClient) -> Result<Counters, failure::Error> {
+    let (rx_count, tx_count) = client.query(&Message::PacketCountersRequest, |msg| match msg {
+        Message::PacketCounters { rx_count, tx_count } => Some((*rx_count, *tx_count)),
+        _ => None
+    })?;
+    let (rx_errors, tx_errors) = client.query(&Message::ErrorCountersRequest, |msg| match msg {
+        Message::ErrorCounters { rx_errors, tx_errors } => Some((*rx_errors, *tx_errors)),
+        _ => None
+    })?;
+
+    Ok(Counters {
+        rx_count,
+        tx_count,
+        rx_errors,
+        tx_errors
+    })
+}
+
+fn stats(client: &mut CandClient, interval: Duration, count: Option<u64>) -> Result<(), failure::Error> {
+    let mut last: Option<(Instant, Counters)> = None;
+    let mut polls = 0;
+    while count != Some(polls) {
+        if polls > 0 {
+            thread::sleep(interval);
+        }
+        polls += 1;
+
+        let counters = poll_counters(client)?;
+        let now = Instant::now();
+
+        let line = counters.values()
+            .iter()
+            .enumerate()
+            .map(|(idx, (name, value))| match &last {
+                Some((last_time, last_counters)) => {
+                    let secs = now.duration_since(*last_time).as_secs_f64();
+                    let delta = value.wrapping_sub(last_counters.values()[idx].1);
+                    format!("{}: {} ({:+.1}/s)", name, value, delta as f64 / secs)
+                }
+                None => format!("{}: {}", name, value)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!("{}", line);
+
+        last = Some((now, counters));
+    }
+    Ok(())
+}
+
+fn reset_cause(client: &mut CandClient) -> Result<(), failure::Error> {
+    let cause = client.query(&Message::ResetCauseRequest, |msg| match msg {
+        Message::ResetCauseReply(cause) => Some(*cause),
+        _ => None
+    })?;
+    println!("Reset cause: {}", cause);
+    Ok(())
+}
+
+fn print_register(client: &mut CandClient, reg: u8) -> Result<(), failure::Error> {
+    let value = client.query(&Message::ReadCtlReg { reg }, |msg| match msg {
+        Message::ReadCtlRegReply { reg: reply_reg, value } if *reply_reg == reg => Some(*value),
+        _ => None
+    })?;
+    match Register::from_addr(reg) {
+        Some(register) => {
+            println!("{:<7} (0x{:02x}) = 0x{:02x}: {}", register, reg, value, register.decode(value))
         }
+        None => println!("        (0x{:02x}) = 0x{:02x}", reg, value)
     }
     Ok(())
 }
 
+fn parse_register(s: &str) -> Result<u8, failure::Error> {
+    match s.parse::<Register>() {
+        Ok(register) => Ok(register.addr()),
+        Err(e) => u8::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|_| e.into())
+    }
+}
+
+fn parse_secs(s: &str) -> Result<Duration, failure::Error> {
+    let secs = s.parse()?;
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| failure::err_msg(format!("Invalid number of seconds {}", s)))
+}
+
+fn is_secs(s: String) -> Result<(), String> {
+    parse_secs(&s).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn parse_speed(s: &str) -> Result<f64, failure::Error> {
+    let speed = s.parse()?;
+    Pacer::new(speed)?;
+    Ok(speed)
+}
+
+fn is_speed(s: String) -> Result<(), String> {
+    parse_speed(&s).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn parse_can_id(s: &str) -> Result<u32, failure::Error> {
+    let id = u32::from_str_radix(s.trim_start_matches("0x"), 16)?;
+    if id > 0x1fffffff {
+        return Err(labctl::error::InvalidCanId.into());
+    }
+    Ok(id)
+}
+
+/// Unused filter slots repeat the first filter, so they accept nothing else
+fn set_filter(client: &mut CandClient, mask: u32, filters: &[u32]) -> Result<(), failure::Error> {
+    let first = *filters.first().ok_or_else(|| failure::err_msg("At least one filter is required"))?;
+    let mut filter = CanFilter {
+        masks: [mask; 2],
+        filters: [first; 6]
+    };
+    filter.filters[..filters.len()].copy_from_slice(filters);
+    client.send(&Message::SetFilter(filter))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn cand_serve(args: &clap::ArgMatches) -> Result<(), failure::Error> {
+    let default_listen = format!("0.0.0.0:{}", labctl::server::DEFAULT_PORT);
+    let listen = args.value_of("listen").unwrap_or(&default_listen);
+    let baud = args.value_of("baud")
+        .map(|baud| baud.parse())
+        .transpose()?
+        .unwrap_or(labctl::serial::DEFAULT_BAUD_RATE);
+
+    let tty = args.value_of("TTY").unwrap();
+    let endpoint = if tty.contains("://") {
+        tty.parse()?
+    } else {
+        Endpoint::Serial { path: PathBuf::from(tty), baud }
+    };
+
+    let gateway = endpoint.connect()?;
+    let server = CandServer::new(gateway)?;
+    server.serve(TcpListener::bind(listen)?)?;
+    Ok(())
+}
+
+/// The settings given as flags, overriding all config files
+fn cli_config(matches: &clap::ArgMatches) -> Result<Config, failure::Error> {
+    Ok(Config {
+        connect: matches.value_of("connect").map(|url| url.parse()).transpose()?,
+        host: matches.value_of("host").map(|host| host.to_string()),
+        port: matches.value_of("port").map(|port| port.parse()).transpose()?,
+        source: matches.value_of("source").map(|source| source.to_string()),
+        timeout: matches.value_of("timeout").map(|timeout| timeout.parse()).transpose()?,
+        tx_settle: matches.value_of("tx_settle").map(|settle| settle.parse()).transpose()?,
+        tx_retries: matches.value_of("tx_retries").map(|retries| retries.parse()).transpose()?,
+        addresses: AddressBook::new()
+    })
+}
+
 fn main() -> Result<(), failure::Error> {
 
     let matches = args().get_matches();
 
-    let host = matches.value_of("host").unwrap();
-    let port = matches.value_of("port").unwrap_or("2342").parse()?;
+    #[cfg(unix)]
+    if let ("cand-serve", Some(serve_args)) = matches.subcommand() {
+        return cand_serve(serve_args);
+    }
+    if let ("export", Some(export_args)) = matches.subcommand() {
+        if export_args.is_present("CAPTURE") {
+            return export_capture(export_args);
+        }
+    }
+
+    let mut config = Config::load()?;
+    let cli = cli_config(&matches)?;
+    config.merge(cli)?;
+
+    let endpoint = config.endpoint()
+        .ok_or_else(|| failure::err_msg("Where to connect to is required (-c or -h, LABCTL_CONNECT or the config file)"))?;
+    let book = &config.addresses;
+    let source = config.source()?;
 
-    let mut s = TcpStream::connect((host, port)).unwrap();
+    let mut client = CandClient::open(&endpoint)?;
+    config.configure(&mut client)?;
 
     match matches.subcommand() {
-        ("monitor", _args) => {
-            monitor(&mut s)?;
+        ("monitor", Some(monitor_args)) => {
+            let filter = frame_filter(monitor_args, book)?;
+            monitor(&mut client, monitor_args.is_present("strict"), monitor_args.is_present("decode"), &filter, book)?;
+        }
+        ("record", Some(record_args)) => {
+            let filter = frame_filter(record_args, book)?;
+            record(&mut client, record_args.value_of("FILE").unwrap(), &filter)?;
+        }
+        ("replay", Some(replay_args)) => {
+            let speed = parse_speed(replay_args.value_of("speed").unwrap_or("1"))?;
+            let filter = frame_filter(replay_args, book)?;
+            replay(&mut client, replay_args.value_of("FILE").unwrap(), speed, &filter)?;
+        }
+        ("send-log", Some(send_args)) => {
+            let speed = parse_speed(send_args.value_of("speed").unwrap_or("1"))?;
+            send_log(&mut client, send_args.value_of("FILE").unwrap(), speed)?;
+        }
+        ("export", Some(export_args)) => {
+            export_live(&mut client, export_args)?;
         }
         ("borg", Some(borg_args)) => {
             match borg_args.subcommand() {
                 ("text", Some(text_args)) => {
                     let text = text_args.value_of("TEXT").unwrap();
-                    let dst = text_args.value_of("DEST")
-                        .unwrap()
-                        .parse()
-                        .unwrap();
+                    let dst = book.resolve(text_args.value_of("DEST").unwrap())?;
                     let now = text_args.is_present("now");
-                    borg_text(&mut s, text, dst)?;
+                    borg_text(&mut client, text, source, dst)?;
                     if now {
-                        borg_mode(&mut s, 1, dst)?;
+                        borg_mode(&mut client, 1, source, dst)?;
                     }
                 },
                 ("mode", Some(mode_args)) => {
-                    let dst = mode_args.value_of("DEST")
-                        .unwrap()
-                        .parse()
-                        .unwrap();
+                    let dst = book.resolve(mode_args.value_of("DEST").unwrap())?;
                     let mode = mode_args.value_of("MODE")
                         .unwrap()
                         .parse()
                         .unwrap();
-                    borg_mode(&mut s, mode, dst)?;
+                    borg_mode(&mut client, mode, source, dst)?;
                 },
                 _ => unreachable!()
             }
         },
         ("power", _) => {
-            bus_power(&mut s.try_clone().unwrap(), &mut s)?;
+            bus_power(&mut client)?;
+        }
+        ("stats", Some(stats_args)) => {
+            let interval = parse_secs(stats_args.value_of("interval").unwrap_or("1"))?;
+            let count = stats_args.value_of("count")
+                .map(|count| count.parse())
+                .transpose()?;
+            stats(&mut client, interval, count)?;
+        }
+        ("filter", Some(filter_args)) => {
+            if filter_args.is_present("all") {
+                client.send(&Message::SetFilter(CanFilter::accept_all()))?;
+            } else {
+                let mask = parse_can_id(filter_args.value_of("MASK").unwrap())?;
+                let filters = filter_args.values_of("FILTER").unwrap()
+                    .map(parse_can_id)
+                    .collect::<Result<Vec<_>, _>>()?;
+                set_filter(&mut client, mask, &filters)?;
+            }
+        }
+        ("gateway", Some(gateway_args)) => {
+            match gateway_args.subcommand() {
+                ("mode", Some(mode_args)) => {
+                    let mode: GatewayMode = mode_args.value_of("MODE")
+                        .unwrap()
+                        .parse()?;
+                    client.send(&Message::SetMode(mode))?;
+                },
+                ("reg", Some(reg_args)) => {
+                    match reg_args.value_of("REG") {
+                        Some(reg) => {
+                            let reg = parse_register(reg)?;
+                            if let Some(value) = reg_args.value_of("set") {
+                                let value = u8::from_str_radix(value.trim_start_matches("0x"), 16)?;
+                                client.send(&Message::WriteCtlReg { reg, value })?;
+                            }
+                            print_register(&mut client, reg)?;
+                        }
+                        None => {
+                            for register in Register::ALL.iter() {
+                                print_register(&mut client, register.addr())?;
+                            }
+                        }
+                    }
+                },
+                ("reset-cause", _) => {
+                    reset_cause(&mut client)?;
+                },
+                _ => unreachable!()
+            }
         }
         _ => unreachable!()
     }
 
-    s.flush()?;
     // Because cand is a piece of crap we need to sleep a tiny bit before closing the socket
     thread::sleep(Duration::from_millis(10));
     //let mut s = TcpStream::connect("10.0.1.4:2342").unwrap();
@@ -0,0 +1,201 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+use crate::cand::GatewayMode;
+use crate::error;
+
+/// Registers of the CAN controller that can be decoded into named fields
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Register {
+    CanStat,
+    CanCtrl,
+    Tec,
+    Rec,
+    Eflg
+}
+
+impl Register {
+    pub const ALL: [Register; 5] = [
+        Register::CanStat,
+        Register::CanCtrl,
+        Register::Tec,
+        Register::Rec,
+        Register::Eflg
+    ];
+
+    pub fn addr(self) -> u8 {
+        match self {
+            Register::CanStat => 0x0e,
+            Register::CanCtrl => 0x0f,
+            Register::Tec => 0x1c,
+            Register::Rec => 0x1d,
+            Register::Eflg => 0x2d
+        }
+    }
+
+    pub fn from_addr(addr: u8) -> Option<Register> {
+        Register::ALL.iter()
+            .copied()
+            .find(|reg| reg.addr() == addr)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::CanStat => "CANSTAT",
+            Register::CanCtrl => "CANCTRL",
+            Register::Tec => "TEC",
+            Register::Rec => "REC",
+            Register::Eflg => "EFLG"
+        }
+    }
+
+    pub fn decode(self, value: u8) -> RegisterValue {
+        match self {
+            Register::CanStat => RegisterValue::CanStat {
+                mode: GatewayMode::from_u8(value >> 5),
+                interrupt: (value >> 1) & 0x07
+            },
+            Register::CanCtrl => RegisterValue::CanCtrl {
+                requested_mode: GatewayMode::from_u8(value >> 5),
+                abort: value & 0x10 != 0,
+                one_shot: value & 0x08 != 0,
+                clock_out: value & 0x04 != 0,
+                clock_prescaler: 1 << (value & 0x03)
+            },
+            Register::Tec => RegisterValue::Tec(value),
+            Register::Rec => RegisterValue::Rec(value),
+            Register::Eflg => RegisterValue::Eflg(ErrorFlags(value))
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Register {
+    type Err = error::UnknownRegister;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Register::ALL.iter()
+            .copied()
+            .find(|reg| reg.name().eq_ignore_ascii_case(s))
+            .ok_or(error::UnknownRegister)
+    }
+}
+
+/// Contents of the EFLG register
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct ErrorFlags(pub u8);
+
+impl ErrorFlags {
+    pub fn rx1_overflow(self) -> bool {
+        self.0 & 0x80 != 0
+    }
+
+    pub fn rx0_overflow(self) -> bool {
+        self.0 & 0x40 != 0
+    }
+
+    pub fn bus_off(self) -> bool {
+        self.0 & 0x20 != 0
+    }
+
+    pub fn tx_error_passive(self) -> bool {
+        self.0 & 0x10 != 0
+    }
+
+    pub fn rx_error_passive(self) -> bool {
+        self.0 & 0x08 != 0
+    }
+
+    pub fn tx_warning(self) -> bool {
+        self.0 & 0x04 != 0
+    }
+
+    pub fn rx_warning(self) -> bool {
+        self.0 & 0x02 != 0
+    }
+
+    pub fn error_warning(self) -> bool {
+        self.0 & 0x01 != 0
+    }
+}
+
+/// A register value split into its fields
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum RegisterValue {
+    CanStat { mode: Option<GatewayMode>, interrupt: u8 },
+    CanCtrl { requested_mode: Option<GatewayMode>, abort: bool, one_shot: bool, clock_out: bool, clock_prescaler: u8 },
+    Tec(u8),
+    Rec(u8),
+    Eflg(ErrorFlags)
+}
+
+fn yes_no(flag: bool) -> &'static str {
+    if flag { "yes" } else { "no" }
+}
+
+fn mode_name(mode: Option<GatewayMode>) -> String {
+    mode.map_or_else(|| "invalid".to_string(), |mode| mode.to_string())
+}
+
+const INTERRUPT_NAMES: [&str; 8] = ["none", "error", "wake-up", "TXB0", "TXB1", "TXB2", "RXB0", "RXB1"];
+
+impl fmt::Display for RegisterValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterValue::CanStat { mode, interrupt } => {
+                write!(f, "mode: {}, interrupt: {}", mode_name(*mode), INTERRUPT_NAMES[*interrupt as usize])
+            }
+            RegisterValue::CanCtrl { requested_mode, abort, one_shot, clock_out, clock_prescaler } => {
+                write!(
+                    f,
+                    "requested mode: {}, abort: {}, one-shot: {}, clkout: {}, prescaler: {}",
+                    mode_name(*requested_mode),
+                    yes_no(*abort),
+                    yes_no(*one_shot),
+                    yes_no(*clock_out),
+                    clock_prescaler
+                )
+            }
+            RegisterValue::Tec(tec) => write!(f, "TEC={}", tec),
+            RegisterValue::Rec(rec) => write!(f, "REC={}", rec),
+            RegisterValue::Eflg(flags) => {
+                write!(
+                    f,
+                    "bus-off: {}, tx passive: {}, rx passive: {}, tx warning: {}, rx warning: {}, \
+                     warning: {}, rx0 overflow: {}, rx1 overflow: {}",
+                    yes_no(flags.bus_off()),
+                    yes_no(flags.tx_error_passive()),
+                    yes_no(flags.rx_error_passive()),
+                    yes_no(flags.tx_warning()),
+                    yes_no(flags.rx_warning()),
+                    yes_no(flags.error_warning()),
+                    yes_no(flags.rx0_overflow()),
+                    yes_no(flags.rx1_overflow())
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cand::GatewayMode;
+    use crate::mcp2515::{Register, RegisterValue};
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(format!("{}", Register::Tec.decode(255)), "TEC=255");
+        assert!(format!("{}", Register::Eflg.decode(0x20)).starts_with("bus-off: yes,"));
+        assert_eq!(
+            Register::CanStat.decode(0x60),
+            RegisterValue::CanStat { mode: Some(GatewayMode::ListenOnly), interrupt: 0 }
+        );
+        assert_eq!(Register::from_addr(0x2d), Some(Register::Eflg));
+        assert_eq!("canctrl".parse::<Register>().unwrap(), Register::CanCtrl);
+    }
+}
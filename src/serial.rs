@@ -0,0 +1,340 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use nix::sys::termios::{self, BaudRate, SetArg, SpecialCharacterIndices};
+use crate::can::check_payload_len;
+use crate::cand::{Message, MAX_MESSAGE_LEN};
+use crate::error::{Error, Result};
+use crate::transport::Transport;
+
+pub use crate::transport::DEFAULT_BAUD_RATE;
+
+/// A partially received frame is dropped if no more bytes arrive within this time
+const INTER_BYTE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Computes the checksum the gateway appends to every frame, using the same algorithm as
+/// avr-libc's `_crc16_update` with an initial value of `0xffff`
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, byte| {
+        let mut crc = crc ^ *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xa001 } else { crc >> 1 };
+        }
+        crc
+    })
+}
+
+/// Appends `msg` in the rs232can framing to `buf`.
+///
+/// On the serial line every message is sent as its type, payload length, payload and a little
+/// endian [crc16] of the preceding bytes. Note that type and length are swapped compared to
+/// the cand framing.
+pub fn encode(msg: &Message, buf: &mut Vec<u8>) -> Result<()> {
+    let len = msg.encoded_len();
+    check_payload_len(len, MAX_MESSAGE_LEN)?;
+
+    let start = buf.len();
+    buf.push(msg.kind());
+    buf.push(len as u8);
+    if let Err(e) = msg.write(buf) {
+        buf.truncate(start);
+        return Err(e);
+    }
+    let crc = crc16(&buf[start..]);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    Ok(())
+}
+
+/// Incremental decoder for the rs232can framing.
+///
+/// Bytes that do not start a valid frame, either because of an unknown message type or a
+/// checksum mismatch, are skipped one at a time until a valid frame is found again. Every time
+/// this happens a resync is flagged, see [SerialDecoder::take_resync].
+#[derive(Debug, Default)]
+pub struct SerialDecoder {
+    buf: Vec<u8>,
+    resync: bool
+}
+
+impl SerialDecoder {
+    pub fn new() -> SerialDecoder {
+        SerialDecoder::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> &mut SerialDecoder {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    /// Number of bytes buffered that do not form a complete frame yet
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Drops a partially received frame
+    pub fn clear(&mut self) {
+        if !self.buf.is_empty() {
+            self.buf.clear();
+            self.resync = true;
+        }
+    }
+
+    /// Returns whether bytes were dropped since the last call
+    pub fn take_resync(&mut self) -> bool {
+        let resync = self.resync;
+        self.resync = false;
+        resync
+    }
+
+    pub fn decode(&mut self) -> Option<Result<Message>> {
+        loop {
+            if self.buf.len() < 2 {
+                return None;
+            }
+
+            // The gateway only knows message types 0x10 to 0x1f
+            let kind = self.buf[0];
+            if !(0x10..=0x1f).contains(&kind) {
+                self.skip();
+                continue;
+            }
+
+            let len = self.buf[1] as usize;
+            if self.buf.len() < len + 4 {
+                return None;
+            }
+
+            let crc = u16::from_le_bytes([self.buf[len + 2], self.buf[len + 3]]);
+            if crc != crc16(&self.buf[..len + 2]) {
+                self.skip();
+                continue;
+            }
+
+            let msg = Message::read(kind, &self.buf[2..len + 2]);
+            self.buf.drain(..len + 4);
+            return Some(msg);
+        }
+    }
+
+    fn skip(&mut self) {
+        self.buf.remove(0);
+        self.resync = true;
+    }
+}
+
+impl Iterator for SerialDecoder {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decode()
+    }
+}
+
+fn baud_rate(baud: u32) -> Result<BaudRate> {
+    match baud {
+        9_600 => Ok(BaudRate::B9600),
+        19_200 => Ok(BaudRate::B19200),
+        38_400 => Ok(BaudRate::B38400),
+        57_600 => Ok(BaudRate::B57600),
+        115_200 => Ok(BaudRate::B115200),
+        230_400 => Ok(BaudRate::B230400),
+        _ => Err(Error::UnsupportedBaudRate(baud))
+    }
+}
+
+/// Configures `port` for raw binary transfer with reads returning after at most 100ms
+fn configure(port: &File, baud: Option<u32>) -> Result<()> {
+    let mut attrs = termios::tcgetattr(port).map_err(io::Error::from)?;
+    termios::cfmakeraw(&mut attrs);
+    if let Some(baud) = baud {
+        termios::cfsetspeed(&mut attrs, baud_rate(baud)?).map_err(io::Error::from)?;
+    }
+    attrs.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+    attrs.control_chars[SpecialCharacterIndices::VTIME as usize] = 1;
+    termios::tcsetattr(port, SetArg::TCSANOW, &attrs).map_err(io::Error::from)?;
+    Ok(())
+}
+
+/// Direct connection to the CAN gateway over its serial port, without a cand in between
+pub struct SerialTransport {
+    port: File,
+    /// Shared between clones, so that frames sent from different threads do not interleave
+    writer: Arc<Mutex<File>>,
+    decoder: SerialDecoder,
+    last_rx: Instant
+}
+
+impl SerialTransport {
+    /// Opens and configures the serial port at `path`
+    pub fn open<P: AsRef<Path>>(path: P, baud: u32) -> Result<SerialTransport> {
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        configure(&port, Some(baud))?;
+        SerialTransport::new(port)
+    }
+
+    /// Uses an already opened terminal, e.g. one side of a pseudo terminal. The terminal is put
+    /// into raw mode but keeps its baud rate.
+    pub fn new(port: File) -> Result<SerialTransport> {
+        configure(&port, None)?;
+        let mut transport = SerialTransport {
+            writer: Arc::new(Mutex::new(port.try_clone()?)),
+            port,
+            decoder: SerialDecoder::new(),
+            last_rx: Instant::now()
+        };
+        // The gateway might be in the middle of a frame from an earlier session
+        transport.send(&Message::Resync)?;
+        Ok(transport)
+    }
+
+    /// Returns a second handle to the same port, see [Transport::try_clone]
+    pub fn try_clone(&self) -> Result<SerialTransport> {
+        Ok(SerialTransport {
+            port: self.port.try_clone()?,
+            writer: self.writer.clone(),
+            decoder: SerialDecoder::new(),
+            last_rx: Instant::now()
+        })
+    }
+
+    pub fn send(&mut self, msg: &Message) -> Result<()> {
+        let mut buf = Vec::new();
+        encode(msg, &mut buf)?;
+
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&buf)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Returns the next message received from the gateway
+    pub fn recv(&mut self) -> Result<Message> {
+        self.read_message(None)
+    }
+
+    /// Like [SerialTransport::recv], but fails with [Error::Timeout] if nothing arrives in time
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Message> {
+        self.read_message(Some(Instant::now() + timeout))
+    }
+
+    fn read_message(&mut self, deadline: Option<Instant>) -> Result<Message> {
+        let mut buf = [0; 256];
+        loop {
+            let msg = self.decoder.decode();
+            if self.decoder.take_resync() {
+                log::warn!("Lost frame synchronisation with the gateway");
+                self.send(&Message::Resync)?;
+            }
+            if let Some(msg) = msg {
+                return msg;
+            }
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(Error::Timeout);
+            }
+
+            // Reads time out after 100ms, see configure
+            let len = match self.port.read(&mut buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+                Err(e) => return Err(e.into())
+            };
+            if len > 0 {
+                self.decoder.feed(&buf[..len]);
+                self.last_rx = Instant::now();
+            } else if self.decoder.buffered() > 0 && self.last_rx.elapsed() >= INTER_BYTE_TIMEOUT {
+                self.decoder.clear();
+            }
+        }
+    }
+}
+
+impl Transport for SerialTransport {
+    fn send(&mut self, msg: &Message) -> Result<()> {
+        SerialTransport::send(self, msg)
+    }
+
+    /// The gateway cannot close the serial port, so this never returns `None`
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<Option<Message>> {
+        self.read_message(timeout.map(|timeout| Instant::now() + timeout)).map(Some)
+    }
+
+    /// The checksum already rejects malformed frames, so there is nothing to make stricter
+    fn set_strict(&mut self, _strict: bool) {}
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(SerialTransport::try_clone(self)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::time::Duration;
+    use nix::pty::openpty;
+    use crate::cand::Message;
+    use crate::serial::{crc16, encode, SerialDecoder, SerialTransport};
+
+    #[test]
+    fn test_crc16() {
+        // Check value of CRC-16/MODBUS, which uses the same polynomial and initial value
+        assert_eq!(crc16(b"123456789"), 0x4b37);
+    }
+
+    #[test]
+    fn test_decoder_resync() {
+        let mut stream = vec![0x00, 0xff];
+        encode(&Message::VersionReply { major: 1, minor: 3 }, &mut stream).unwrap();
+        let mut corrupt = Vec::new();
+        encode(&Message::Ping, &mut corrupt).unwrap();
+        corrupt[2] ^= 0x01;
+        stream.extend_from_slice(&corrupt);
+        encode(&Message::Ping, &mut stream).unwrap();
+
+        let mut decoder = SerialDecoder::new();
+        let output: Vec<_> = decoder.feed(&stream).map(|msg| msg.unwrap()).collect();
+        assert_eq!(format!("{:?}", output), "[Message::VersionReply { minor: 3, major: 1 }, Message::Ping]");
+        assert!(decoder.take_resync());
+        assert!(!decoder.take_resync());
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn test_pty() {
+        let pty = openpty(None, None).unwrap();
+        let mut gateway = File::from(pty.master);
+        let mut transport = SerialTransport::new(File::from(pty.slave)).unwrap();
+
+        let mut frame = Vec::new();
+        encode(&Message::Resync, &mut frame).unwrap();
+        let mut received = vec![0; frame.len()];
+        gateway.read_exact(&mut received).unwrap();
+        assert_eq!(received, frame);
+
+        // A truncated frame followed by silence is dropped
+        let mut frame = vec![0x17];
+        gateway.write_all(&frame).unwrap();
+        assert!(transport.recv_timeout(Duration::from_millis(300)).is_err());
+        frame.clear();
+        encode(&Message::VersionReply { major: 1, minor: 3 }, &mut frame).unwrap();
+        gateway.write_all(&frame).unwrap();
+        match transport.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Message::VersionReply { major: 1, minor: 3 } => {}
+            other => panic!("unexpected message {:?}", other)
+        }
+
+        transport.send(&Message::VersionRequest).unwrap();
+        let mut expected = Vec::new();
+        encode(&Message::Resync, &mut expected).unwrap();
+        encode(&Message::VersionRequest, &mut expected).unwrap();
+        let mut received = vec![0; expected.len()];
+        gateway.read_exact(&mut received).unwrap();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,361 @@
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+use crate::cand::{read_packet_strict, write_packet_to_cand, Message};
+use crate::error::{Error, Result};
+use crate::transport::Transport;
+
+pub use crate::transport::DEFAULT_PORT;
+
+/// Number of messages queued for a client before further messages to it are dropped
+const CLIENT_QUEUE: usize = 1024;
+
+/// How often [CandServer::serve] checks whether the gateway is still there
+const GATEWAY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Client {
+    id: usize,
+    queue: SyncSender<Message>
+}
+
+#[derive(Default)]
+struct Clients {
+    next_id: usize,
+    clients: Vec<Client>
+}
+
+impl Clients {
+    fn add(&mut self, queue: SyncSender<Message>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.clients.push(Client { id, queue });
+        id
+    }
+
+    fn remove(&mut self, id: usize) {
+        self.clients.retain(|client| client.id != id);
+    }
+
+    /// Queues `msg` for all clients except `except`, without waiting for slow clients
+    fn broadcast(&mut self, msg: &Message, except: Option<usize>) {
+        self.clients.retain(|client| {
+            if Some(client.id) == except {
+                return true;
+            }
+            match client.queue.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Client {} does not keep up, dropping message", client.id);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false
+            }
+        });
+    }
+}
+
+struct Shared {
+    clients: Mutex<Clients>,
+    gateway: Sender<Message>,
+    version: Mutex<Option<(u8, u8)>>
+}
+
+/// A cand compatible server sharing one gateway between any number of TCP clients.
+///
+/// Messages from the gateway are sent to all clients, frames from a client are sent to the
+/// gateway and all other clients. All other requests are passed on to the gateway, except for
+/// pings and version requests, which the server answers itself.
+///
+/// Every client has its own queue of pending messages, so a slow client does not hold up the
+/// others. A client that shuts down its sending side still gets all messages until it closes the
+/// connection completely.
+pub struct CandServer {
+    shared: Arc<Shared>,
+    /// Why the connection to the gateway was lost
+    gateway_lost: Mutex<Receiver<Error>>
+}
+
+impl CandServer {
+    /// Starts passing messages to and from `gateway` in background threads.
+    ///
+    /// The gateway is usually a [SerialTransport](crate::serial::SerialTransport), but may as
+    /// well be another cand.
+    pub fn new<T: Transport + 'static>(gateway: T) -> Result<CandServer> {
+        let (gateway_tx, gateway_rx) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            clients: Mutex::new(Clients::default()),
+            gateway: gateway_tx,
+            version: Mutex::new(None)
+        });
+
+        let mut writer = gateway.try_clone()?;
+        thread::spawn(move || {
+            for msg in gateway_rx {
+                if let Err(e) = writer.send(&msg) {
+                    log::error!("Sending to the gateway failed: {}", e);
+                }
+            }
+        });
+
+        let mut reader = gateway;
+        let reader_shared = shared.clone();
+        let (lost_tx, gateway_lost) = mpsc::channel();
+        thread::spawn(move || {
+            let error = loop {
+                match reader.recv_timeout(None) {
+                    Ok(Some(msg)) => {
+                        if let Message::VersionReply { major, minor } = msg {
+                            *reader_shared.version.lock().unwrap() = Some((major, minor));
+                        }
+                        reader_shared.clients.lock().unwrap().broadcast(&msg, None);
+                    }
+                    Ok(None) => {
+                        log::error!("The gateway closed the connection");
+                        break Error::Disconnected;
+                    }
+                    // Undecodable messages are reported otherwise, this is the connection failing
+                    Err(Error::IOError(e)) => {
+                        log::error!("Receiving from the gateway failed: {}", e);
+                        break Error::IOError(e);
+                    }
+                    Err(e) => log::warn!("Dropping message from the gateway: {}", e)
+                }
+            };
+            let _ = lost_tx.send(error);
+        });
+
+        // Learn the version to answer version requests
+        let _ = shared.gateway.send(Message::VersionRequest);
+
+        Ok(CandServer {
+            shared,
+            gateway_lost: Mutex::new(gateway_lost)
+        })
+    }
+
+    /// Accepts clients on `listener` until the connection to the gateway is lost.
+    ///
+    /// # Errors
+    /// [Error::Disconnected] if the gateway closed the connection, or the error receiving from it
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        let gateway_lost = self.gateway_lost.lock().unwrap();
+        listener.set_nonblocking(true)?;
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.add_client(stream) {
+                        log::warn!("Serving client failed: {}", e);
+                    }
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => log::warn!("Accepting client failed: {}", e)
+            }
+            match gateway_lost.recv_timeout(GATEWAY_CHECK_INTERVAL) {
+                Ok(e) => return Err(e),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::Disconnected)
+            }
+        }
+    }
+
+    /// Serves a single client in background threads
+    pub fn add_client(&self, stream: TcpStream) -> Result<()> {
+        // Streams accepted by serve inherit the listener's nonblocking mode on some platforms
+        stream.set_nonblocking(false)?;
+        let mut write = stream.try_clone()?;
+        let (queue, outgoing) = mpsc::sync_channel(CLIENT_QUEUE);
+        let id = self.shared.clients.lock().unwrap().add(queue.clone());
+
+        thread::spawn(move || {
+            for msg in outgoing {
+                match write_packet_to_cand(&mut write, &msg) {
+                    Ok(()) => {}
+                    // The client will be removed with the next broadcast
+                    Err(Error::IOError(_)) => break,
+                    Err(e) => log::warn!("Dropping message to client {}: {}", id, e)
+                }
+            }
+        });
+
+        let shared = self.shared.clone();
+        let mut read = stream;
+        thread::spawn(move || {
+            loop {
+                match read_packet_strict(&mut read) {
+                    Ok(Some(msg)) => handle_request(&shared, id, &queue, msg),
+                    // Half closed, keep sending until the client closes completely
+                    Ok(None) => return,
+                    Err(Error::IOError(_)) => break,
+                    Err(e) => log::warn!("Dropping message from client {}: {}", id, e)
+                }
+            }
+            shared.clients.lock().unwrap().remove(id);
+        });
+
+        Ok(())
+    }
+}
+
+fn handle_request(shared: &Shared, id: usize, reply: &SyncSender<Message>, msg: Message) {
+    match msg {
+        Message::Ping => {
+            let _ = reply.try_send(Message::Ping);
+        }
+        Message::VersionRequest => {
+            match *shared.version.lock().unwrap() {
+                Some((major, minor)) => {
+                    let _ = reply.try_send(Message::VersionReply { major, minor });
+                }
+                None => {
+                    let _ = shared.gateway.send(Message::VersionRequest);
+                }
+            }
+        }
+        // The link to the gateway is kept in sync by the server
+        Message::Resync => {}
+        Message::Frame(frame) => {
+            let msg = Message::Frame(frame);
+            shared.clients.lock().unwrap().broadcast(&msg, Some(id));
+            let _ = shared.gateway.send(msg);
+        }
+        msg => {
+            let _ = shared.gateway.send(msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use nix::pty::openpty;
+    use crate::can::{CanAddr, CanPacket};
+    use crate::cand::{write_packet_to_cand, CandClient, Message};
+    use crate::serial::{self, SerialDecoder, SerialTransport};
+    use crate::error::Error;
+    use crate::server::CandServer;
+    use crate::transport::{MemoryTransport, SocketTransport, Transport};
+
+    #[test]
+    fn test_server() {
+        let pty = openpty(None, None).unwrap();
+        let mut gateway = File::from(pty.master);
+        let server = CandServer::new(SerialTransport::new(File::from(pty.slave)).unwrap()).unwrap();
+
+        let frame = CanPacket::new(CanAddr::new(0x42, 1).unwrap(), CanAddr::new(0, 0x23).unwrap(), vec![1]).unwrap();
+        let (frames_tx, frames) = mpsc::channel();
+        let mut gateway_write = gateway.try_clone().unwrap();
+        let reply_frame = frame.clone();
+        thread::spawn(move || {
+            let mut decoder = SerialDecoder::new();
+            let mut buf = [0; 64];
+            loop {
+                let len = gateway.read(&mut buf).unwrap();
+                for msg in decoder.feed(&buf[..len]) {
+                    let reply = match msg.unwrap() {
+                        Message::VersionRequest => Message::VersionReply { major: 1, minor: 3 },
+                        Message::Frame(frame) => {
+                            frames_tx.send(frame).unwrap();
+                            Message::Frame(reply_frame.clone())
+                        }
+                        _ => continue
+                    };
+                    let mut out = Vec::new();
+                    serial::encode(&reply, &mut out).unwrap();
+                    gateway_write.write_all(&out).unwrap();
+                }
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener).unwrap());
+
+        let mut sender = CandClient::connect(addr).unwrap();
+        let mut listener = CandClient::connect(addr).unwrap();
+        sender.set_tx_settle(Duration::from_millis(0), 0);
+        assert_eq!(sender.version().unwrap(), (1, 3));
+        sender.ping().unwrap();
+
+        // Sending and closing right away must not lose the frame
+        let mut oneshot = TcpStream::connect(addr).unwrap();
+        write_packet_to_cand(&mut oneshot, &Message::Frame(frame.clone())).unwrap();
+        oneshot.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(frames.recv_timeout(Duration::from_secs(1)).unwrap(), frame);
+
+        // Frames from one client reach the other ones, answers from the bus reach all of them
+        assert_eq!(next_frame(&mut listener), frame);
+        assert_eq!(next_frame(&mut listener), frame);
+        assert_eq!(next_frame(&mut sender), frame);
+    }
+
+    #[test]
+    fn test_gateway_lost() {
+        let (gateway, end) = MemoryTransport::pair();
+        let server = CandServer::new(gateway).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = thread::spawn(move || server.serve(listener));
+
+        let mut client = CandClient::connect(addr).unwrap();
+        client.ping().unwrap();
+        drop(end);
+        match serving.join().unwrap() {
+            Err(Error::Disconnected) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        let (gateway, mut end) = MemoryTransport::pair();
+        let server = CandServer::new(gateway).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener));
+        assert_eq!(end.recv_timeout(None).unwrap(), Some(Message::VersionRequest));
+
+        let mut broken = TcpStream::connect(addr).unwrap();
+        let mut listener = CandClient::connect(addr).unwrap();
+        listener.ping().unwrap();
+
+        // A DLC beyond a classic CAN frame, a frame shorter than its DLC and a valid frame
+        let mut oversize = vec![14, 0x11, 0x01, 0x00, 0x82, 0x11, 9];
+        oversize.extend_from_slice(&[0; 9]);
+        broken.write_all(&oversize).unwrap();
+        broken.write_all(&[6, 0x11, 0x01, 0x00, 0x82, 0x11, 2, 0x01]).unwrap();
+        let frame = CanPacket::new(CanAddr::new(0x01, 2).unwrap(), CanAddr::new(0, 0x23).unwrap(), vec![1]).unwrap();
+        write_packet_to_cand(&mut broken, &Message::Frame(frame.clone())).unwrap();
+
+        assert_eq!(next_frame(&mut listener), frame);
+        assert_eq!(end.recv_timeout(Some(Duration::from_secs(1))).unwrap(), Some(Message::Frame(frame.clone())));
+
+        // A frame from the gateway that cannot be encoded for the clients is skipped
+        let addr = CanAddr::new(0x42, 1).unwrap();
+        end.send(&Message::Frame(CanPacket { src: addr, dest: addr, payload: vec![0; 12] })).unwrap();
+        end.send(&Message::Frame(frame.clone())).unwrap();
+        assert_eq!(next_frame(&mut listener), frame);
+
+        let mut client = CandClient::new(SocketTransport::new(broken));
+        client.ping().unwrap();
+    }
+
+    /// Skips everything but frames, e.g. gateway answers to requests of other clients
+    fn next_frame(client: &mut CandClient) -> CanPacket {
+        loop {
+            match client.recv_timeout(Duration::from_secs(1)).unwrap() {
+                Some(Message::Frame(received)) => return received,
+                Some(_) => {}
+                None => panic!("connection closed")
+            }
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use crate::can::CanPacket;
+#[cfg(feature = "async")]
+use crate::cand::AsyncCandClient;
+use crate::cand::{BusPower, Message};
+use crate::transport::{MemoryTransport, Transport};
+
+/// Handles a frame addressed to a simulated node and returns the frames it sends in response
+pub type NodeHandler = Box<dyn FnMut(&CanPacket) -> Vec<CanPacket> + Send>;
+
+struct Node {
+    addr: u8,
+    handler: Arc<Mutex<NodeHandler>>
+}
+
+struct Gateway {
+    id: usize,
+    transport: Box<dyn Transport>
+}
+
+struct State {
+    version: (u8, u8),
+    firmware_id: String,
+    bus_power: BusPower,
+    nodes: Vec<Node>,
+    gateways: Vec<Gateway>,
+    next_gateway: usize,
+    history: Vec<CanPacket>,
+    /// Frames waiting to be put on the bus, with the gateway they came from
+    pending: VecDeque<(CanPacket, Option<usize>)>,
+    /// Whether a thread is putting the pending frames on the bus
+    transmitting: bool
+}
+
+/// Puts `frame` on the bus, passing it to all nodes and gateways except the one it came from.
+/// Frames sent by nodes in response are put on the bus in turn.
+///
+/// Node handlers run without holding the lock on `state`, so they may use the [SimBus]. While
+/// another thread is transmitting, `frame` is only queued and put on the bus by that thread.
+fn transmit(state: &Mutex<State>, frame: CanPacket, from: Option<usize>) {
+    {
+        let mut state = state.lock().unwrap();
+        state.pending.push_back((frame, from));
+        if state.transmitting {
+            return;
+        }
+        state.transmitting = true;
+    }
+
+    loop {
+        let (frame, handlers) = {
+            let mut state = state.lock().unwrap();
+            let (frame, from) = match state.pending.pop_front() {
+                Some(pending) => pending,
+                None => {
+                    state.transmitting = false;
+                    return;
+                }
+            };
+
+            state.history.push(frame.clone());
+            let msg = Message::Frame(frame.clone());
+            state.gateways.retain_mut(|gateway| {
+                Some(gateway.id) == from || gateway.transport.send(&msg).is_ok()
+            });
+
+            let handlers = state.nodes.iter()
+                .filter(|node| frame.dest.addr() == node.addr || frame.dest.addr() == 0xff)
+                .map(|node| node.handler.clone())
+                .collect::<Vec<_>>();
+            (frame, handlers)
+        };
+
+        let responses = handlers.iter()
+            .flat_map(|handler| (handler.lock().unwrap())(&frame))
+            .collect::<Vec<_>>();
+        state.lock().unwrap().pending.extend(responses.into_iter().map(|response| (response, None)));
+    }
+}
+
+/// A virtual CAN bus with simulated LAP nodes, reachable through fake gateways.
+///
+/// The gateways answer pings, version, firmware ID and bus power requests with the configured
+/// values and pass frames to and from the bus. Other requests are ignored.
+#[derive(Clone)]
+pub struct SimBus {
+    state: Arc<Mutex<State>>
+}
+
+impl SimBus {
+    pub fn new() -> SimBus {
+        SimBus {
+            state: Arc::new(Mutex::new(State {
+                version: (1, 0),
+                firmware_id: "labctl simulated gateway".to_string(),
+                bus_power: BusPower {
+                    v: 0,
+                    i: 0,
+                    reference: 0,
+                    gnd: 0
+                },
+                nodes: Vec::new(),
+                gateways: Vec::new(),
+                next_gateway: 0,
+                history: Vec::new(),
+                pending: VecDeque::new(),
+                transmitting: false
+            }))
+        }
+    }
+
+    /// Sets the protocol version reported by the gateways
+    pub fn set_version(&self, major: u8, minor: u8) {
+        self.state.lock().unwrap().version = (major, minor);
+    }
+
+    pub fn set_firmware_id(&self, id: &str) {
+        self.state.lock().unwrap().firmware_id = id.to_string();
+    }
+
+    pub fn set_bus_power(&self, power: BusPower) {
+        self.state.lock().unwrap().bus_power = power;
+    }
+
+    /// Attaches a node that gets all frames sent to address `addr` on any port, including
+    /// broadcasts to address 0xff.
+    ///
+    /// The frames returned by `handler` are put on the bus right away. The handler may use the
+    /// bus itself, e.g. to [inject](SimBus::inject) frames.
+    pub fn add_node<F>(&self, addr: u8, handler: F)
+        where F: FnMut(&CanPacket) -> Vec<CanPacket> + Send + 'static {
+        self.state.lock().unwrap().nodes.push(Node {
+            addr,
+            handler: Arc::new(Mutex::new(Box::new(handler)))
+        });
+    }
+
+    /// Puts `frame` on the bus as if a node sent it on its own
+    pub fn inject(&self, frame: CanPacket) {
+        transmit(&self.state, frame, None);
+    }
+
+    /// Returns all frames that were on the bus so far, in order
+    pub fn history(&self) -> Vec<CanPacket> {
+        self.state.lock().unwrap().history.clone()
+    }
+
+    /// Attaches a new fake gateway to the bus and returns the client side of its connection.
+    ///
+    /// The gateway is served by a background thread until the connection is dropped.
+    pub fn connect(&self) -> MemoryTransport {
+        let (client, mut gateway) = MemoryTransport::pair();
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_gateway;
+            state.next_gateway += 1;
+            state.gateways.push(Gateway {
+                id,
+                transport: gateway.try_clone().unwrap()
+            });
+            id
+        };
+
+        let state = self.state.clone();
+        thread::spawn(move || {
+            while let Ok(Some(msg)) = gateway.recv_timeout(None) {
+                if let Message::Frame(frame) = msg {
+                    transmit(&state, frame, Some(id));
+                    continue;
+                }
+
+                let state = state.lock().unwrap();
+                let reply = match msg {
+                    Message::Ping => Message::Ping,
+                    Message::VersionRequest => {
+                        let (major, minor) = state.version;
+                        Message::VersionReply { major, minor }
+                    }
+                    Message::FirmwareIdRequest => Message::FirmwareIdResponse(state.firmware_id.clone()),
+                    Message::BusPowerRequest => {
+                        let BusPower { v, i, reference, gnd } = state.bus_power;
+                        Message::BusPowerResponse { v, i, reference, gnd }
+                    }
+                    _ => continue
+                };
+                if gateway.send(&reply).is_err() {
+                    break;
+                }
+            }
+            state.lock().unwrap().gateways.retain(|gateway| gateway.id != id);
+        });
+
+        client
+    }
+
+    /// Like [SimBus::connect], for an async client on the current tokio runtime
+    #[cfg(feature = "async")]
+    pub fn connect_async(&self) -> AsyncCandClient {
+        AsyncCandClient::with_transport(self.connect()).unwrap()
+    }
+}
+
+impl Default for SimBus {
+    fn default() -> SimBus {
+        SimBus::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::can::{CanAddr, CanPacket};
+    use crate::cand::{BusPower, CandClient, Message};
+    use crate::sim::SimBus;
+
+    #[test]
+    fn test_sim() {
+        let bus = SimBus::new();
+        bus.set_version(2, 1);
+        bus.set_firmware_id("sim");
+        let power = BusPower { v: 800, i: 20, reference: 512, gnd: 3 };
+        bus.set_bus_power(power);
+
+        // Answers every frame by echoing it back to the sender
+        bus.add_node(0x24, |frame| vec![CanPacket {
+            src: frame.dest,
+            dest: frame.src,
+            payload: frame.payload.clone()
+        }]);
+
+        let mut client = CandClient::new(bus.connect());
+        let mut other = CandClient::new(bus.connect());
+        client.set_timeout(Duration::from_millis(200));
+        other.set_timeout(Duration::from_millis(200));
+
+        assert_eq!(client.version().unwrap(), (2, 1));
+        assert_eq!(client.firmware_id().unwrap(), "sim");
+        assert_eq!(client.bus_power().unwrap(), power);
+        client.ping().unwrap();
+
+        let request = CanPacket::new(CanAddr::new(0, 0x23).unwrap(), CanAddr::new(0x24, 2).unwrap(), vec![1, 2]).unwrap();
+        let response = CanPacket::new(CanAddr::new(0x24, 2).unwrap(), CanAddr::new(0, 0x23).unwrap(), vec![1, 2]).unwrap();
+        client.send_frame(&request).unwrap();
+        match client.recv_timeout(Duration::from_millis(200)).unwrap() {
+            Some(Message::Frame(frame)) => assert_eq!(frame, response),
+            other => panic!("unexpected message {:?}", other)
+        }
+
+        // The other gateway sees both frames, while frames to nobody just end up in the history
+        for expected in [&request, &response] {
+            match other.recv_timeout(Duration::from_millis(200)).unwrap() {
+                Some(Message::Frame(frame)) => assert_eq!(frame, *expected),
+                other => panic!("unexpected message {:?}", other)
+            }
+        }
+        let unanswered = CanPacket::new(CanAddr::new(0x30, 1).unwrap(), CanAddr::new(0x25, 1).unwrap(), vec![]).unwrap();
+        bus.inject(unanswered.clone());
+        assert_eq!(bus.history(), vec![request, response, unanswered]);
+    }
+
+    #[test]
+    fn test_handler_uses_bus() {
+        let bus = SimBus::new();
+        let node = CanAddr::new(0x24, 2).unwrap();
+        let announce = CanPacket::new(node, CanAddr::new(0x30, 0).unwrap(), vec![0xaa]).unwrap();
+
+        // Announces itself before answering, from within the handler
+        let handler_bus = bus.clone();
+        let handler_announce = announce.clone();
+        bus.add_node(0x24, move |frame| {
+            handler_bus.inject(handler_announce.clone());
+            assert!(handler_bus.history().contains(frame));
+            vec![CanPacket::new(frame.dest, frame.src, vec![handler_bus.history().len() as u8]).unwrap()]
+        });
+
+        let mut client = CandClient::new(bus.connect());
+        let request = CanPacket::new(CanAddr::new(0, 0x23).unwrap(), node, vec![1]).unwrap();
+        let response = CanPacket::new(node, CanAddr::new(0, 0x23).unwrap(), vec![1]).unwrap();
+        client.set_tx_settle(Duration::from_millis(0), 0);
+        client.send_frame(&request).unwrap();
+        for expected in [&announce, &response] {
+            match client.recv_timeout(Duration::from_millis(200)).unwrap() {
+                Some(Message::Frame(frame)) => assert_eq!(frame, *expected),
+                other => panic!("unexpected message {:?}", other)
+            }
+        }
+        assert_eq!(bus.history(), vec![request, announce, response]);
+    }
+}
@@ -0,0 +1,370 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use serde::{de::Error as _, Deserialize, Deserializer};
+use crate::cand::{write_packet_to_cand, Decoder, Message};
+use crate::error::{self, Error, Result};
+
+/// A link to a cand or directly to the gateway that carries [Message]s
+pub trait Transport: Send {
+    fn send(&mut self, msg: &Message) -> Result<()>;
+
+    /// Waits for the next message, at most for `timeout` if given.
+    ///
+    /// Returns `None` once the link was closed by the other side.
+    ///
+    /// # Errors
+    /// [Error::Timeout] if nothing arrived in time
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<Option<Message>>;
+
+    /// Decode received messages using [Message::read_strict]
+    fn set_strict(&mut self, strict: bool);
+
+    /// Returns a second handle to the same link, e.g. for sending from another thread.
+    ///
+    /// Both handles should not be used for receiving at the same time.
+    fn try_clone(&self) -> Result<Box<dyn Transport>>;
+}
+
+impl Transport for Box<dyn Transport> {
+    fn send(&mut self, msg: &Message) -> Result<()> {
+        (**self).send(msg)
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<Option<Message>> {
+        (**self).recv_timeout(timeout)
+    }
+
+    fn set_strict(&mut self, strict: bool) {
+        (**self).set_strict(strict)
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>> {
+        (**self).try_clone()
+    }
+}
+
+/// Byte streams the cand framing can be spoken over
+pub trait Socket: Read + Write + Send + Sized + 'static {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Socket for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl Socket for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+/// The cand framing over a stream socket
+pub struct SocketTransport<S: Socket> {
+    socket: S,
+    decoder: Decoder
+}
+
+pub type TcpTransport = SocketTransport<TcpStream>;
+
+#[cfg(unix)]
+pub type UnixTransport = SocketTransport<UnixStream>;
+
+impl<S: Socket> SocketTransport<S> {
+    pub fn new(socket: S) -> SocketTransport<S> {
+        SocketTransport {
+            socket,
+            decoder: Decoder::new()
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+}
+
+impl<S: Socket> Transport for SocketTransport<S> {
+    fn send(&mut self, msg: &Message) -> Result<()> {
+        write_packet_to_cand(&mut self.socket, msg)
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<Option<Message>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut buf = [0; 512];
+        loop {
+            if let Some(msg) = self.decoder.decode() {
+                return msg.map(Some);
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                    Some(deadline - now)
+                }
+                None => None
+            };
+            self.socket.set_read_timeout(timeout)?;
+
+            match self.socket.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(len) => {
+                    self.decoder.feed(&buf[..len]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into())
+            }
+        }
+    }
+
+    fn set_strict(&mut self, strict: bool) {
+        self.decoder.set_strict(strict);
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(SocketTransport::new(self.socket.try_clone()?)))
+    }
+}
+
+/// One end of an in-memory link, see [MemoryTransport::pair]
+pub struct MemoryTransport {
+    tx: Sender<Message>,
+    rx: Arc<Mutex<Receiver<Message>>>
+}
+
+impl MemoryTransport {
+    /// Creates two connected ends, messages sent on one end are received on the other one
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (a_tx, a_rx) = mpsc::channel();
+        let (b_tx, b_rx) = mpsc::channel();
+        let a = MemoryTransport {
+            tx: a_tx,
+            rx: Arc::new(Mutex::new(b_rx))
+        };
+        let b = MemoryTransport {
+            tx: b_tx,
+            rx: Arc::new(Mutex::new(a_rx))
+        };
+        (a, b)
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&mut self, msg: &Message) -> Result<()> {
+        self.tx.send(msg.clone())
+            .map_err(|_| Error::Disconnected)
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<Option<Message>> {
+        let rx = self.rx.lock().unwrap();
+        match timeout {
+            Some(timeout) => match rx.recv_timeout(timeout) {
+                Ok(msg) => Ok(Some(msg)),
+                Err(RecvTimeoutError::Timeout) => Err(Error::Timeout),
+                Err(RecvTimeoutError::Disconnected) => Ok(None)
+            },
+            None => Ok(rx.recv().ok())
+        }
+    }
+
+    /// Messages are passed on as they are, so there is nothing to decode
+    fn set_strict(&mut self, _strict: bool) {}
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(MemoryTransport {
+            tx: self.tx.clone(),
+            rx: self.rx.clone()
+        }))
+    }
+}
+
+/// Where to connect to, in the form `tcp://host:port`, `unix:///path/to/socket` or
+/// `serial:///dev/tty?baud=115200`.
+///
+/// The port defaults to 2342 and the baud rate to the gateway's default.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Endpoint {
+    Tcp { host: String, port: u16 },
+    Unix(PathBuf),
+    Serial { path: PathBuf, baud: u32 }
+}
+
+/// Default TCP port of cand
+pub const DEFAULT_PORT: u16 = 2342;
+
+/// Baud rate the rs232can gateway firmware uses by default
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+impl Endpoint {
+    pub fn connect(&self) -> Result<Box<dyn Transport>> {
+        match self {
+            Endpoint::Tcp { host, port } => {
+                Ok(Box::new(TcpTransport::new(TcpStream::connect((host.as_str(), *port))?)))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(Box::new(UnixTransport::new(UnixStream::connect(path)?))),
+            #[cfg(unix)]
+            Endpoint::Serial { path, baud } => Ok(Box::new(crate::serial::SerialTransport::open(path, *baud)?)),
+            #[cfg(not(unix))]
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "transport not supported on this platform").into())
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp { host, port } if host.contains(':') => write!(f, "tcp://[{}]:{}", host, port),
+            Endpoint::Tcp { host, port } => write!(f, "tcp://{}:{}", host, port),
+            Endpoint::Unix(path) => write!(f, "unix://{}", path.display()),
+            Endpoint::Serial { path, baud } => write!(f, "serial://{}?baud={}", path.display(), baud)
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = error::InvalidEndpoint;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (scheme, rest) = match s.find("://") {
+            Some(idx) => (&s[..idx], &s[idx + 3..]),
+            None => return Err(error::InvalidEndpoint)
+        };
+
+        match scheme {
+            "tcp" => {
+                // An IPv6 address has to be bracketed to tell it from the port
+                let (host, port) = match rest.strip_prefix('[') {
+                    Some(bracketed) => match bracketed.split_once(']') {
+                        Some((host, "")) => (host, None),
+                        Some((host, port)) => (host, Some(port.strip_prefix(':').ok_or(error::InvalidEndpoint)?)),
+                        None => return Err(error::InvalidEndpoint)
+                    },
+                    None => match rest.split_once(':') {
+                        Some((host, port)) => (host, Some(port)),
+                        None => (rest, None)
+                    }
+                };
+                let port = match port {
+                    Some(port) => port.parse().map_err(|_| error::InvalidEndpoint)?,
+                    None => DEFAULT_PORT
+                };
+                if host.is_empty() {
+                    return Err(error::InvalidEndpoint);
+                }
+                Ok(Endpoint::Tcp { host: host.to_string(), port })
+            }
+            "unix" if !rest.is_empty() => Ok(Endpoint::Unix(PathBuf::from(rest))),
+            "serial" => {
+                let (path, baud) = match rest.find("?baud=") {
+                    Some(idx) => {
+                        let baud = rest[idx + 6..].parse().map_err(|_| error::InvalidEndpoint)?;
+                        (&rest[..idx], baud)
+                    }
+                    None => (rest, DEFAULT_BAUD_RATE)
+                };
+                if path.is_empty() {
+                    return Err(error::InvalidEndpoint);
+                }
+                Ok(Endpoint::Serial { path: PathBuf::from(path), baud })
+            }
+            _ => Err(error::InvalidEndpoint)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Endpoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|_| D::Error::custom(format!("invalid endpoint {:?}", s)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use crate::cand::{CandClient, Message};
+    use crate::error::Error;
+    use crate::transport::{Endpoint, MemoryTransport, Transport};
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            "tcp://10.0.1.4".parse::<Endpoint>().unwrap(),
+            Endpoint::Tcp { host: "10.0.1.4".to_string(), port: 2342 }
+        );
+        assert_eq!(
+            "tcp://[::1]:1234".parse::<Endpoint>().unwrap(),
+            Endpoint::Tcp { host: "::1".to_string(), port: 1234 }
+        );
+        assert_eq!(
+            "unix:///run/cand.sock".parse::<Endpoint>().unwrap(),
+            Endpoint::Unix(PathBuf::from("/run/cand.sock"))
+        );
+        assert_eq!(
+            "serial:///dev/ttyUSB0?baud=57600".parse::<Endpoint>().unwrap(),
+            Endpoint::Serial { path: PathBuf::from("/dev/ttyUSB0"), baud: 57600 }
+        );
+        assert_eq!(
+            "tcp://[::1]".parse::<Endpoint>().unwrap(),
+            Endpoint::Tcp { host: "::1".to_string(), port: 2342 }
+        );
+        assert_eq!(Endpoint::Tcp { host: "::1".to_string(), port: 2342 }.to_string(), "tcp://[::1]:2342");
+        assert!("tcp://::1".parse::<Endpoint>().is_err());
+        assert!("tcp://fe80::1".parse::<Endpoint>().is_err());
+        assert!("tcp://[::1".parse::<Endpoint>().is_err());
+        assert!("tcp://[::1]1234".parse::<Endpoint>().is_err());
+        assert!("tcp://host:port".parse::<Endpoint>().is_err());
+        assert!("10.0.1.4:2342".parse::<Endpoint>().is_err());
+        assert!("serial://".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn test_memory() {
+        let (client, mut gateway) = MemoryTransport::pair();
+        let mut client = CandClient::new(client);
+        client.set_timeout(Duration::from_millis(100));
+
+        gateway.send(&Message::VersionReply { major: 1, minor: 3 }).unwrap();
+        assert_eq!(client.version().unwrap(), (1, 3));
+        match gateway.recv_timeout(Some(Duration::from_millis(100))).unwrap() {
+            Some(Message::VersionRequest) => {}
+            other => panic!("unexpected message {:?}", other)
+        }
+        match gateway.recv_timeout(Some(Duration::from_millis(10))) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+
+        drop(gateway);
+        match client.ping() {
+            Err(Error::Disconnected) => {}
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+}